@@ -0,0 +1,179 @@
+use cache_server::bench_support::{
+    extract_particles, ExtractedParticles, LodField, ParticleArrays, ParticleFloat,
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ndarray::{s, Array, Array1, Array2, Array3, ArrayView1};
+
+const N_PARTICLES: usize = 2_000_000;
+
+/// The extraction loop of `calc_lod` before it was parallelised, kept verbatim as the baseline.
+fn extract_sequential<F: ParticleFloat>(
+    arrays: ParticleArrays,
+    relevant_ids: &[i64],
+    relevant_shifts: &[[f64; 3]],
+    origin: [f64; 3],
+    fields: &[LodField],
+) -> ExtractedParticles<F> {
+    let ParticleArrays {
+        splines,
+        densities,
+        coordinates,
+        voronoi_diameter_extended,
+    } = arrays;
+    let n_particles = relevant_ids.len();
+
+    let with_splines = fields.contains(&LodField::Splines);
+    let with_densities = fields.contains(&LodField::Densities);
+    let with_coordinates = fields.contains(&LodField::Coordinates);
+    let with_voronoi_diameter = fields.contains(&LodField::VoronoiDiameter);
+    // Number of particles in the arrays of a field
+    let n_of = |wanted: bool| if wanted { n_particles } else { 0 };
+
+    // Allocate result arrays
+    let mut splines_a: Vec<F> = vec![F::default(); 3 * n_of(with_splines)];
+    let mut splines_b: Vec<F> = vec![F::default(); 3 * n_of(with_splines)];
+    let mut splines_c: Vec<F> = vec![F::default(); 3 * n_of(with_splines)];
+    let mut splines_d: Vec<F> = vec![F::default(); 3 * n_of(with_splines)];
+
+    let mut relevant_densities_flat: Vec<F> = vec![F::default(); n_of(with_densities) * 2];
+
+    let mut relevant_coordinates: Vec<Vec<F>> = vec![vec![]; n_of(with_coordinates)];
+    let mut relevant_voronoi_diameter_extended: Vec<F> =
+        vec![F::default(); n_of(with_voronoi_diameter)];
+
+    let relative = |values: ArrayView1<f64>, shift: &[f64; 3]| -> Vec<F> {
+        values
+            .iter()
+            .zip(origin.iter())
+            .zip(shift.iter())
+            .map(|((value, origin), shift)| F::from_f64(value + shift - origin))
+            .collect()
+    };
+    let convert =
+        |values: ArrayView1<f64>| -> Vec<F> { values.iter().map(|v| F::from_f64(*v)).collect() };
+
+    let mut min_d = f64::INFINITY;
+    let mut max_d = f64::NEG_INFINITY;
+
+    // Extract relevant data and copy into result arrays
+    for (idx, (id, shift)) in relevant_ids
+        .iter()
+        .copied()
+        .zip(relevant_shifts)
+        .enumerate()
+    {
+        if with_splines {
+            splines_a.splice(
+                idx * 3..(idx + 1) * 3,
+                relative(splines.slice(s![id as usize, 0, ..]), shift),
+            );
+            splines_b.splice(
+                idx * 3..(idx + 1) * 3,
+                convert(splines.slice(s![id as usize, 1, ..])),
+            );
+            splines_c.splice(
+                idx * 3..(idx + 1) * 3,
+                convert(splines.slice(s![id as usize, 2, ..])),
+            );
+            splines_d.splice(
+                idx * 3..(idx + 1) * 3,
+                convert(splines.slice(s![id as usize, 3, ..])),
+            );
+        }
+
+        if with_densities {
+            for (row, offset) in [(0, idx), (1, idx + n_particles)] {
+                let density = densities[[row, id as usize]];
+                min_d = min_d.min(density);
+                max_d = max_d.max(density);
+                relevant_densities_flat[offset] = F::from_f64(density);
+            }
+        }
+
+        if with_coordinates {
+            relevant_coordinates[idx] = relative(coordinates.slice(s![id as usize, ..]), shift);
+        }
+        if with_voronoi_diameter {
+            relevant_voronoi_diameter_extended[idx] =
+                F::from_f64(voronoi_diameter_extended[[id as usize]]);
+        }
+    }
+
+    if n_particles == 0 || !with_densities {
+        min_d = 0.0;
+        max_d = 0.0;
+    }
+
+    ExtractedParticles {
+        splines_a,
+        splines_b,
+        splines_c,
+        splines_d,
+        relevant_densities_flat,
+        relevant_coordinates,
+        relevant_voronoi_diameter_extended,
+        min_d,
+        max_d,
+    }
+}
+
+fn bench_extraction(c: &mut Criterion) {
+    // A snapshot with arbitrary values
+    let splines: Array3<f64> =
+        Array::from_shape_fn((N_PARTICLES, 4, 3), |(i, j, k)| (i + j + k) as f64);
+    let densities: Array2<f64> = Array::from_shape_fn((2, N_PARTICLES), |(i, j)| (i + j) as f64);
+    let coordinates: Array2<f64> =
+        Array::from_shape_fn((N_PARTICLES, 3), |(i, j)| (i * 3 + j) as f64);
+    let voronoi_diameter_extended: Array1<f64> = Array::from_shape_fn(N_PARTICLES, |i| i as f64);
+    let arrays = ParticleArrays {
+        splines: splines.view(),
+        densities: densities.view(),
+        coordinates: coordinates.view(),
+        voronoi_diameter_extended: voronoi_diameter_extended.view(),
+    };
+    let origin = [1.0, 2.0, 3.0];
+
+    let mut group = c.benchmark_group("extraction");
+    group.sample_size(10);
+    for n_particles in [10_000, 100_000, 1_000_000] {
+        // Spread the ids over the snapshot like the leafs of a view do.
+        let stride = N_PARTICLES / n_particles;
+        let ids: Vec<i64> = (0..n_particles).map(|i| (i * stride) as i64).collect();
+        // Some leafs are periodic images, their particles are shifted by a box length.
+        let shifts: Vec<[f64; 3]> = (0..n_particles)
+            .map(|i| {
+                if i % 4 == 0 {
+                    [100.0, 0.0, 0.0]
+                } else {
+                    [0.0; 3]
+                }
+            })
+            .collect();
+        group.throughput(Throughput::Elements(n_particles as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("sequential", n_particles),
+            &ids,
+            |b, ids| {
+                b.iter(|| {
+                    extract_sequential::<f32>(
+                        arrays,
+                        black_box(ids),
+                        &shifts,
+                        origin,
+                        &LodField::ALL,
+                    )
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("parallel", n_particles), &ids, |b, ids| {
+            b.iter(|| {
+                extract_particles::<f32>(arrays, black_box(ids), &shifts, origin, &LodField::ALL)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_extraction);
+criterion_main!(benches);
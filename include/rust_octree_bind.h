@@ -8,4 +8,5 @@
 using namespace open3d::geometry;
 
 std::shared_ptr<Octree> load_octree_from_file(rust::String file_name);
-rust::Vec<int64_t> get_intersecting_node(std::shared_ptr<Octree> octree, Viewbox viewbox);
+rust::Vec<NodeInfo> get_intersecting_node_info(std::shared_ptr<Octree> octree, Viewbox viewbox);
+rust::Vec<NodeInfo> get_intersecting_node_info_frustum(std::shared_ptr<Octree> octree, Frustum frustum);
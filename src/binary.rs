@@ -0,0 +1,529 @@
+use actix_web::{http::header, HttpRequest};
+
+use super::dto::{LodField, LodResult, ParticleFloat};
+
+/// Content type of the binary encoding of a `LodResult`.
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+pub const MAGIC: &[u8; 4] = b"LODB";
+pub const VERSION: u32 = 7;
+pub const HEADER_SIZE: usize = 96;
+
+/// The bytes of each float array are shuffled, see `encode`.
+pub const FLAG_SHUFFLED: u32 = 1;
+/// The values of a colouring field follow the voronoi diameter.
+pub const FLAG_COLOR: u32 = 2;
+/// Interpolated positions and densities follow the colouring field.
+pub const FLAG_INTERPOLATED: u32 = 4;
+
+/// Whether the client asked for the binary encoding via the `Accept` header.
+pub fn accepts_binary(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.split(';').next().unwrap_or("").trim() == CONTENT_TYPE)
+}
+
+/// Encode a `LodResult` as little-endian typed arrays that can be mapped directly into
+/// WebGL buffers.
+///
+/// Header, 96 bytes:
+///
+/// | offset | type     | field                                      |
+/// |--------|----------|--------------------------------------------|
+/// | 0      | [u8; 4]  | magic `LODB`                               |
+/// | 4      | u32      | version                                    |
+/// | 8      | u32      | snapnum                                    |
+/// | 12     | u32      | n particles `n`                            |
+/// | 16     | u32      | n level of detail entries `k`              |
+/// | 20     | u32      | element size `s`, 4 for f32 and 8 for f64  |
+/// | 24     | u32      | flags                                      |
+/// | 28     | u32      | fields, see `field_mask`                   |
+/// | 32     | f64      | min density                                |
+/// | 40     | f64      | max density                                |
+/// | 48     | [f64; 3] | origin of the positions, zero if absolute  |
+/// | 72     | f64      | min of the colouring field                 |
+/// | 80     | f64      | max of the colouring field                 |
+/// | 88     | f64      | time of the interpolated values            |
+///
+/// Followed by the float arrays splines a, b, c, d (`3n` each), densities (`2n`, both rows
+/// after each other), coordinates (`3n`) and voronoi diameter (`n`) with elements of `s`
+/// bytes, arrays of fields that were not requested are left out. With `FLAG_COLOR` the
+/// values of the colouring field follow (`n`), with `FLAG_INTERPOLATED` the interpolated
+/// positions (`3n`) and densities (`n`). For clients without a session the level of detail
+/// follows as `k` i64 node indices and `k` i64 levels, after zero bytes that pad the float
+/// arrays to a multiple of 8. All float arrays start at offsets that are multiples of `s`,
+/// the level of detail at a multiple of 8.
+///
+/// With `FLAG_SHUFFLED` each float array of `m` elements is stored byte-shuffled: first byte
+/// 0 of all elements, then byte 1 of all elements and so on, so byte `j` of element `i` is
+/// at `j * m + i`. Neighbouring floats share sign, exponent and high mantissa bytes, which
+/// compresses a lot better when they are next to each other.
+pub fn encode<F: ParticleFloat>(lod_result: &LodResult<F>, shuffle: bool) -> Vec<u8> {
+    let n_particles = lod_result.n_particles;
+    let level_of_detail: Vec<(i64, i64)> = lod_result
+        .client_level_of_detail
+        .as_ref()
+        .map(|level_of_detail| {
+            level_of_detail
+                .iter()
+                .map(|(node, lod)| (*node, *lod))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut buffer = Vec::with_capacity(encoded_len(lod_result));
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&VERSION.to_le_bytes());
+    buffer.extend_from_slice(&(lod_result.snapshot_id as u32).to_le_bytes());
+    buffer.extend_from_slice(&(n_particles as u32).to_le_bytes());
+    buffer.extend_from_slice(&(level_of_detail.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&(F::SIZE as u32).to_le_bytes());
+    let mut flags = if shuffle { FLAG_SHUFFLED } else { 0 };
+    if lod_result.color.is_some() {
+        flags |= FLAG_COLOR;
+    }
+    if lod_result.interpolated.is_some() {
+        flags |= FLAG_INTERPOLATED;
+    }
+    buffer.extend_from_slice(&flags.to_le_bytes());
+    buffer.extend_from_slice(&field_mask(&lod_result.fields).to_le_bytes());
+    buffer.extend_from_slice(&lod_result.min_d.to_le_bytes());
+    buffer.extend_from_slice(&lod_result.max_d.to_le_bytes());
+    for origin in lod_result.origin.unwrap_or([0.0; 3]) {
+        buffer.extend_from_slice(&origin.to_le_bytes());
+    }
+    let (color_min, color_max) = lod_result
+        .color
+        .as_ref()
+        .map_or((0.0, 0.0), |color| (color.min, color.max));
+    buffer.extend_from_slice(&color_min.to_le_bytes());
+    buffer.extend_from_slice(&color_max.to_le_bytes());
+    let time = lod_result
+        .interpolated
+        .as_ref()
+        .map_or(0.0, |interpolated| interpolated.time);
+    buffer.extend_from_slice(&time.to_le_bytes());
+
+    let mut extend = |values: &[F]| {
+        let start = buffer.len();
+        for value in values {
+            value.extend_le_bytes(&mut buffer);
+        }
+        if shuffle {
+            shuffle_bytes(&mut buffer[start..], F::SIZE);
+        }
+    };
+    extend(&lod_result.splines_a);
+    extend(&lod_result.splines_b);
+    extend(&lod_result.splines_c);
+    extend(&lod_result.splines_d);
+    extend(&lod_result.relevant_densities_flat);
+    let coordinates: Vec<F> = lod_result
+        .relevant_coordinates
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+    extend(&coordinates);
+    extend(&lod_result.relevant_voronoi_diameter_extended);
+    if let Some(color) = &lod_result.color {
+        extend(&color.values);
+    }
+    if let Some(interpolated) = &lod_result.interpolated {
+        extend(&interpolated.positions);
+        extend(&interpolated.densities);
+    }
+
+    if lod_result.client_level_of_detail.is_some() {
+        buffer.resize(buffer.len() + padding(buffer.len()), 0);
+    }
+    for (node, _) in &level_of_detail {
+        buffer.extend_from_slice(&node.to_le_bytes());
+    }
+    for (_, lod) in &level_of_detail {
+        buffer.extend_from_slice(&lod.to_le_bytes());
+    }
+    buffer
+}
+
+/// Length in bytes of `encode(lod_result, _)`, shuffling does not change it.
+pub fn encoded_len<F: ParticleFloat>(lod_result: &LodResult<F>) -> usize {
+    let n_floats = lod_result.splines_a.len()
+        + lod_result.splines_b.len()
+        + lod_result.splines_c.len()
+        + lod_result.splines_d.len()
+        + lod_result.relevant_densities_flat.len()
+        + lod_result
+            .relevant_coordinates
+            .iter()
+            .map(Vec::len)
+            .sum::<usize>()
+        + lod_result.relevant_voronoi_diameter_extended.len()
+        + lod_result
+            .color
+            .as_ref()
+            .map_or(0, |color| color.values.len())
+        + lod_result.interpolated.as_ref().map_or(0, |interpolated| {
+            interpolated.positions.len() + interpolated.densities.len()
+        });
+    let floats_end = HEADER_SIZE + F::SIZE * n_floats;
+    match &lod_result.client_level_of_detail {
+        Some(level_of_detail) => floats_end + padding(floats_end) + 8 * 2 * level_of_detail.len(),
+        None => floats_end,
+    }
+}
+
+/// Zero bytes after `len` bytes up to the next multiple of 8.
+fn padding(len: usize) -> usize {
+    (8 - len % 8) % 8
+}
+
+/// Bit `i` is set if field `LodField::ALL[i]` is contained: 1 splines, 2 densities,
+/// 4 coordinates and 8 voronoi diameter.
+pub fn field_mask(fields: &[LodField]) -> u32 {
+    LodField::ALL
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| fields.contains(field))
+        .map(|(bit, _)| 1 << bit)
+        .sum()
+}
+
+/// Transpose `bytes`, seen as elements of `element_size` bytes, into byte planes.
+fn shuffle_bytes(bytes: &mut [u8], element_size: usize) {
+    let n_elements = bytes.len() / element_size;
+    let elements = bytes.to_vec();
+    for (idx, element) in elements.chunks_exact(element_size).enumerate() {
+        for (byte_idx, byte) in element.iter().enumerate() {
+            bytes[byte_idx * n_elements + idx] = *byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dto::{ColorValues, InterpolatedValues};
+    use super::*;
+    use std::collections::HashMap;
+
+    fn f64_at(buffer: &[u8], offset: usize) -> f64 {
+        f64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_encode_layout() {
+        let n_particles = 2;
+        let lod_result: LodResult<f64> = LodResult {
+            splines_a: vec![1.0; 3 * n_particles],
+            splines_b: vec![2.0; 3 * n_particles],
+            splines_c: vec![3.0; 3 * n_particles],
+            splines_d: vec![4.0; 3 * n_particles],
+            relevant_densities_flat: vec![5.0, 6.0, 7.0, 8.0],
+            relevant_coordinates: vec![vec![9.0, 10.0, 11.0], vec![12.0, 13.0, 14.0]],
+            relevant_voronoi_diameter_extended: vec![15.0, 16.0],
+            client_level_of_detail: Some(HashMap::from([(3, 1)])),
+            min_d: 5.0,
+            max_d: 8.0,
+            n_particles,
+            fields: LodField::ALL.to_vec(),
+            snapshot_id: 99,
+            node_indices: Some(vec![3]),
+            origin: None,
+            color: None,
+            interpolated: None,
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, false);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        assert_eq!(buffer.len(), HEADER_SIZE + 8 * (18 * n_particles + 2));
+        assert_eq!(&buffer[0..4], MAGIC);
+        assert_eq!(u32_at(&buffer, 4), VERSION);
+        assert_eq!(u32_at(&buffer, 8), 99);
+        assert_eq!(u32_at(&buffer, 12), 2);
+        assert_eq!(u32_at(&buffer, 16), 1);
+        assert_eq!(u32_at(&buffer, 20), 8);
+        assert_eq!(u32_at(&buffer, 24), 0);
+        assert_eq!(u32_at(&buffer, 28), 15);
+        assert_eq!(f64_at(&buffer, 40), 8.0);
+
+        let densities = HEADER_SIZE + 8 * 12 * n_particles;
+        assert_eq!(f64_at(&buffer, densities), 5.0);
+        let coordinates = densities + 8 * 2 * n_particles;
+        assert_eq!(f64_at(&buffer, coordinates + 8 * 3), 12.0);
+        let voronoi = coordinates + 8 * 3 * n_particles;
+        assert_eq!(f64_at(&buffer, voronoi + 8), 16.0);
+        let level_of_detail = voronoi + 8 * n_particles;
+        assert_eq!(
+            i64::from_le_bytes(
+                buffer[level_of_detail..level_of_detail + 8]
+                    .try_into()
+                    .unwrap()
+            ),
+            3
+        );
+    }
+
+    #[test]
+    fn test_encode_f32_relative() {
+        let lod_result: LodResult<f32> = LodResult {
+            splines_a: vec![1.0; 3],
+            splines_b: vec![2.0; 3],
+            splines_c: vec![3.0; 3],
+            splines_d: vec![4.0; 3],
+            relevant_densities_flat: vec![5.0, 6.0],
+            relevant_coordinates: vec![vec![-0.5, 0.0, 0.5]],
+            relevant_voronoi_diameter_extended: vec![7.0],
+            client_level_of_detail: None,
+            min_d: 5.0,
+            max_d: 6.0,
+            n_particles: 1,
+            fields: LodField::ALL.to_vec(),
+            snapshot_id: 99,
+            node_indices: None,
+            origin: Some([100.0, 200.0, 300.0]),
+            color: None,
+            interpolated: None,
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, false);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        assert_eq!(buffer.len(), HEADER_SIZE + 4 * 18);
+        assert_eq!(u32_at(&buffer, 20), 4);
+        assert_eq!(f64_at(&buffer, 56), 200.0);
+        let coordinates = HEADER_SIZE + 4 * 14;
+        assert_eq!(
+            f32::from_le_bytes(buffer[coordinates..coordinates + 4].try_into().unwrap()),
+            -0.5
+        );
+    }
+
+    #[test]
+    fn test_encode_selected_fields() {
+        let lod_result: LodResult<f32> = LodResult {
+            splines_a: vec![],
+            splines_b: vec![],
+            splines_c: vec![],
+            splines_d: vec![],
+            relevant_densities_flat: vec![5.0, 6.0],
+            relevant_coordinates: vec![vec![1.0, 2.0, 3.0]],
+            relevant_voronoi_diameter_extended: vec![],
+            client_level_of_detail: None,
+            min_d: 5.0,
+            max_d: 6.0,
+            n_particles: 1,
+            fields: vec![LodField::Coordinates, LodField::Densities],
+            snapshot_id: 99,
+            node_indices: None,
+            origin: None,
+            color: Some(ColorValues {
+                field: "Temperature".to_string(),
+                values: vec![1e4],
+                min: 1e4,
+                max: 1e4,
+            }),
+            interpolated: None,
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, false);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        assert_eq!(buffer.len(), HEADER_SIZE + 4 * 6);
+        assert_eq!(u32_at(&buffer, 24), FLAG_COLOR);
+        assert_eq!(u32_at(&buffer, 28), 6);
+        assert_eq!(f64_at(&buffer, 80), 1e4);
+        assert_eq!(
+            f32::from_le_bytes(buffer[HEADER_SIZE..HEADER_SIZE + 4].try_into().unwrap()),
+            5.0
+        );
+        let color = HEADER_SIZE + 4 * 5;
+        assert_eq!(
+            f32::from_le_bytes(buffer[color..color + 4].try_into().unwrap()),
+            1e4
+        );
+    }
+
+    #[test]
+    fn test_encode_interpolated() {
+        let lod_result: LodResult<f32> = LodResult {
+            splines_a: vec![],
+            splines_b: vec![],
+            splines_c: vec![],
+            splines_d: vec![],
+            relevant_densities_flat: vec![],
+            relevant_coordinates: vec![],
+            relevant_voronoi_diameter_extended: vec![2.0],
+            client_level_of_detail: None,
+            min_d: 5.0,
+            max_d: 6.0,
+            n_particles: 1,
+            fields: vec![LodField::VoronoiDiameter],
+            snapshot_id: 99,
+            node_indices: None,
+            origin: None,
+            color: None,
+            interpolated: Some(InterpolatedValues {
+                time: 0.25,
+                positions: vec![1.0, 2.0, 3.0],
+                densities: vec![5.25],
+            }),
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, false);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        assert_eq!(buffer.len(), HEADER_SIZE + 4 * 5);
+        assert_eq!(u32_at(&buffer, 24), FLAG_INTERPOLATED);
+        assert_eq!(f64_at(&buffer, 88), 0.25);
+        let positions = HEADER_SIZE + 4;
+        assert_eq!(
+            f32::from_le_bytes(buffer[positions + 8..positions + 12].try_into().unwrap()),
+            3.0
+        );
+        let densities = positions + 4 * 3;
+        assert_eq!(
+            f32::from_le_bytes(buffer[densities..densities + 4].try_into().unwrap()),
+            5.25
+        );
+    }
+
+    #[test]
+    fn test_encode_pads_level_of_detail() {
+        // Densities and coordinates of three particles are 15 floats, 60 bytes.
+        let n_particles = 3;
+        let lod_result: LodResult<f32> = LodResult {
+            splines_a: vec![],
+            splines_b: vec![],
+            splines_c: vec![],
+            splines_d: vec![],
+            relevant_densities_flat: vec![5.0; 2 * n_particles],
+            relevant_coordinates: vec![vec![1.0, 2.0, 3.0]; n_particles],
+            relevant_voronoi_diameter_extended: vec![],
+            client_level_of_detail: Some(HashMap::from([(7, 2)])),
+            min_d: 5.0,
+            max_d: 5.0,
+            n_particles,
+            fields: vec![LodField::Densities, LodField::Coordinates],
+            snapshot_id: 99,
+            node_indices: Some(vec![7]),
+            origin: None,
+            color: None,
+            interpolated: None,
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, false);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        let level_of_detail = HEADER_SIZE + 4 * 15 + 4;
+        assert_eq!(level_of_detail % 8, 0);
+        assert_eq!(buffer.len(), level_of_detail + 8 * 2);
+        assert_eq!(&buffer[level_of_detail - 4..level_of_detail], &[0; 4]);
+        let at = |offset: usize| i64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+        assert_eq!(at(level_of_detail), 7);
+        assert_eq!(at(level_of_detail + 8), 2);
+    }
+
+    #[test]
+    fn test_encode_pads_after_color() {
+        // All fields of one particle are 18 floats, the colouring field makes it 19.
+        let lod_result: LodResult<f32> = LodResult {
+            splines_a: vec![1.0; 3],
+            splines_b: vec![2.0; 3],
+            splines_c: vec![3.0; 3],
+            splines_d: vec![4.0; 3],
+            relevant_densities_flat: vec![5.0, 6.0],
+            relevant_coordinates: vec![vec![1.0, 2.0, 3.0]],
+            relevant_voronoi_diameter_extended: vec![7.0],
+            client_level_of_detail: Some(HashMap::from([(4, 1)])),
+            min_d: 5.0,
+            max_d: 6.0,
+            n_particles: 1,
+            fields: LodField::ALL.to_vec(),
+            snapshot_id: 99,
+            node_indices: Some(vec![4]),
+            origin: None,
+            color: Some(ColorValues {
+                field: "Temperature".to_string(),
+                values: vec![1e4],
+                min: 1e4,
+                max: 1e4,
+            }),
+            interpolated: None,
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, true);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        let level_of_detail = HEADER_SIZE + 4 * 19 + 4;
+        assert_eq!(buffer.len(), level_of_detail + 8 * 2);
+        assert_eq!(
+            i64::from_le_bytes(
+                buffer[level_of_detail..level_of_detail + 8]
+                    .try_into()
+                    .unwrap()
+            ),
+            4
+        );
+    }
+
+    #[test]
+    fn test_encode_pads_after_interpolated() {
+        // The voronoi diameter and the interpolated values of one particle are 5 floats.
+        let lod_result: LodResult<f32> = LodResult {
+            splines_a: vec![],
+            splines_b: vec![],
+            splines_c: vec![],
+            splines_d: vec![],
+            relevant_densities_flat: vec![],
+            relevant_coordinates: vec![],
+            relevant_voronoi_diameter_extended: vec![2.0],
+            client_level_of_detail: Some(HashMap::from([(4, 1)])),
+            min_d: 5.0,
+            max_d: 6.0,
+            n_particles: 1,
+            fields: vec![LodField::VoronoiDiameter],
+            snapshot_id: 99,
+            node_indices: Some(vec![4]),
+            origin: None,
+            color: None,
+            interpolated: Some(InterpolatedValues {
+                time: 0.25,
+                positions: vec![1.0, 2.0, 3.0],
+                densities: vec![5.25],
+            }),
+            refined: false,
+        };
+
+        let buffer = encode(&lod_result, false);
+        assert_eq!(buffer.len(), encoded_len(&lod_result));
+        let level_of_detail = HEADER_SIZE + 4 * 5 + 4;
+        assert_eq!(buffer.len(), level_of_detail + 8 * 2);
+        let densities = HEADER_SIZE + 4 * 4;
+        assert_eq!(
+            f32::from_le_bytes(buffer[densities..densities + 4].try_into().unwrap()),
+            5.25
+        );
+        assert_eq!(
+            i64::from_le_bytes(
+                buffer[level_of_detail + 8..level_of_detail + 16]
+                    .try_into()
+                    .unwrap()
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn test_shuffle_bytes() {
+        let mut bytes = [1, 2, 3, 4, 5, 6, 7, 8];
+        shuffle_bytes(&mut bytes, 4);
+        assert_eq!(bytes, [1, 5, 2, 6, 3, 7, 4, 8]);
+    }
+}
@@ -12,6 +12,23 @@ pub mod ffi {
         box_max: RustVec3,
     }
 
+    /// Points with `normal * p + offset >= 0` are on the inner side.
+    struct Plane {
+        normal: RustVec3,
+        offset: f64,
+    }
+
+    struct Frustum {
+        planes: Vec<Plane>,
+    }
+
+    /// Leaf of the octree, `index` points into the particle list scan.
+    struct NodeInfo {
+        index: i64,
+        origin: RustVec3,
+        size: f64,
+    }
+
     #[namespace = "open3d::geometry"]
     unsafe extern "C++" {
         include!("cache-server/include/Octree.h");
@@ -23,7 +40,12 @@ pub mod ffi {
         include!("cache-server/include/rust_octree_bind.h");
 
         fn load_octree_from_file(file_name: String) -> SharedPtr<Octree>;
-        fn get_intersecting_node(octree: SharedPtr<Octree>, viewbox: Viewbox) -> Vec<i64>;
+        fn get_intersecting_node_info(octree: SharedPtr<Octree>, viewbox: Viewbox)
+            -> Vec<NodeInfo>;
+        fn get_intersecting_node_info_frustum(
+            octree: SharedPtr<Octree>,
+            frustum: Frustum,
+        ) -> Vec<NodeInfo>;
     }
 }
 
@@ -33,6 +55,22 @@ impl ffi::RustVec3 {
     }
 }
 
+impl ffi::NodeInfo {
+    /// Distance from `point` to the closest point of the node, zero inside of it.
+    pub fn distance_to(&self, point: [f64; 3]) -> f64 {
+        let min = [self.origin.x, self.origin.y, self.origin.z];
+        (0..3)
+            .map(|i| {
+                let d = (min[i] - point[i])
+                    .max(point[i] - min[i] - self.size)
+                    .max(0.0);
+                d * d
+            })
+            .sum::<f64>()
+            .sqrt()
+    }
+}
+
 unsafe impl Send for ffi::Octree {}
 unsafe impl Sync for ffi::Octree {}
 
@@ -50,10 +88,10 @@ mod tests {
             box_min: ffi::RustVec3::new(2001.0, 2000.0, 2000.0),
             box_max: ffi::RustVec3::new(2504.0, 2500.0, 2506.0),
         };
-        let values = ffi::get_intersecting_node(octree, viewbox);
+        let values = ffi::get_intersecting_node_info(octree, viewbox);
         println!("Length {}", values.len());
         for value in &values {
-            println!("{}", value);
+            println!("{} {}", value.index, value.size);
         }
         assert!(values.len() > 0);
     }
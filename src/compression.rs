@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::io::Write;
+
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, Error, HttpRequest};
+
+use super::dto::{CompressionConfig, WebServiceConfig};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Pick the encoding from an `Accept-Encoding` header, zstd before brotli before gzip.
+/// Encodings with `q=0` are refused.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim();
+            let refused = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (!refused).then_some(coding)
+        })
+        .collect();
+    [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|encoding| {
+            accepted
+                .iter()
+                .any(|coding| coding.eq_ignore_ascii_case(encoding.as_str()))
+        })
+}
+
+/// The encoding a response to `req` will be compressed with if it is large enough.
+pub fn negotiated(req: &HttpRequest, cfg: &CompressionConfig) -> Option<Encoding> {
+    if !cfg.enabled {
+        return None;
+    }
+    req.headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate)
+}
+
+/// Whether a successful response of `len` bytes to `req` is compressed by `compress_response`.
+pub fn compresses(req: &HttpRequest, cfg: &CompressionConfig, len: usize) -> bool {
+    len >= cfg.min_size && negotiated(req, cfg).is_some()
+}
+
+pub fn compress(
+    encoding: Encoding,
+    cfg: &CompressionConfig,
+    bytes: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Zstd => zstd::encode_all(bytes, cfg.zstd_level),
+        Encoding::Brotli => {
+            let mut compressed = vec![];
+            {
+                let mut writer =
+                    brotli::CompressorWriter::new(&mut compressed, 64 * 1024, cfg.brotli_level, 22);
+                writer.write_all(bytes)?;
+            }
+            Ok(compressed)
+        }
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::with_capacity(bytes.len() / 2),
+                flate2::Compression::new(cfg.gzip_level),
+            );
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Middleware for `wrap_fn` that compresses successful responses with the encoding the client
+/// accepts, following the `compression` section of the config.
+pub fn compress_response<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let cfg = req
+        .app_data::<web::Data<WebServiceConfig>>()
+        .map(|cfg| cfg.compression.clone())
+        .unwrap_or_default();
+    let encoding = negotiated(req.request(), &cfg);
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await?;
+        let encoding = match encoding {
+            Some(encoding)
+                if response.status().is_success()
+                    && !response.headers().contains_key(header::CONTENT_ENCODING) =>
+            {
+                encoding
+            }
+            _ => return Ok(response.map_into_boxed_body()),
+        };
+
+        let (req, response) = response.into_parts();
+        let (mut response, body) = response.into_parts();
+        let bytes = to_bytes(body)
+            .await
+            .map_err(|err| ErrorInternalServerError(err.into().to_string()))?;
+        if bytes.len() < cfg.min_size {
+            return Ok(ServiceResponse::new(req, response.set_body(bytes)).map_into_boxed_body());
+        }
+
+        let compressed = web::block(move || compress(encoding, &cfg, &bytes))
+            .await?
+            .map_err(|err| ErrorInternalServerError(format!("Compression failed: {:?}", err)))?;
+        response.headers_mut().insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        Ok(ServiceResponse::new(req, response.set_body(compressed)).map_into_boxed_body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, zstd;q=0.5"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("br;q=0, GZIP"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+    }
+
+    #[test]
+    fn test_compresses() {
+        let cfg = CompressionConfig::default();
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_http_request();
+        assert!(compresses(&req, &cfg, cfg.min_size));
+        assert!(!compresses(&req, &cfg, cfg.min_size - 1));
+
+        let identity = actix_web::test::TestRequest::default().to_http_request();
+        assert!(!compresses(&identity, &cfg, cfg.min_size));
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let cfg = CompressionConfig::default();
+        let bytes: Vec<u8> = (0..10_000).map(|i| (i % 7) as u8).collect();
+
+        let zstd = compress(Encoding::Zstd, &cfg, &bytes).unwrap();
+        assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), bytes);
+
+        let brotli = compress(Encoding::Brotli, &cfg, &bytes).unwrap();
+        let mut decoded = vec![];
+        brotli::Decompressor::new(&brotli[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes);
+
+        let gzip = compress(Encoding::Gzip, &cfg, &bytes).unwrap();
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes);
+        assert!(gzip.len() < bytes.len());
+    }
+}
@@ -1,28 +1,43 @@
 use actix::prelude::*;
 use rand::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use actix_web::rt::task::spawn_blocking;
 use cxx::SharedPtr;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 
-use ndarray::{Array1, Array2, Array3};
-use ndarray_npy::read_npy;
+use ndarray::{Ix1, Ix2, Ix3};
 
-use super::bind::ffi::{load_octree_from_file, Octree};
+use super::bind::ffi::Octree;
+use super::dto::{
+    CurrentCacheResponse, EvictionPolicy, FileChangePolicy, LoadedField, LodField, PrefetchConfig,
+    WebServiceConfig,
+};
+use super::fields::ParticleField;
+use super::npy::NpyArray;
+use super::source::{self, LoadedFields, NpyDirSource, SnapshotSource};
+use super::watch::{file_watcher, FileFingerprint, FilesChanged};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use serde::Serialize;
 
 #[derive(Message)]
 #[rtype(result = "isize")]
 pub struct RandU;
 
+/// The source a simulation is loaded from, to query it off the actor thread.
 #[derive(Message)]
-#[rtype(result = "String")]
-pub struct BaseDirRequest;
+#[rtype(result = "Arc<dyn SnapshotSource>")]
+pub struct SnapshotSourceRequest {
+    pub simulation: String,
+}
 
 #[derive(Message)]
-#[rtype(result = "Arc<anyhow::Result<HashMap<String, Vec<usize>>>>")]
+#[rtype(result = "Arc<anyhow::Result<CurrentCacheResponse>>")]
 pub struct CachedEntriesRequest;
 
 #[derive(Message, Eq, Hash, PartialEq, Serialize, Clone)]
@@ -32,36 +47,98 @@ pub struct CacheRequest {
     pub snapshot_id: usize,
 }
 
+/// A snapshot if it is cached. Neither loads it nor counts as an access, so it cannot evict
+/// other snapshots.
+#[derive(Message)]
+#[rtype(result = "Option<Arc<CacheEntry>>")]
+pub struct PeekRequest {
+    pub simulation: String,
+    pub snapshot_id: usize,
+}
+
+/// Load a snapshot explicitly, e.g. from an administrator. Resolves once the entry is cached.
+#[derive(Message)]
+#[rtype(result = "anyhow::Result<usize>")]
+pub struct LoadRequest {
+    pub simulation: String,
+    pub snapshot_id: usize,
+}
+
+/// Drop a single snapshot from the cache, a load of it that is still running is not cached.
+/// Resolves to whether it was cached or loading.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct EvictRequest {
+    pub simulation: String,
+    pub snapshot_id: usize,
+}
+
+/// Drop every snapshot from the cache and cancel running loads. Resolves to the number of
+/// evicted snapshots, cancelled loads are not counted.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct ClearCacheRequest;
+
 pub struct CacheEntry {
-    pub particle_list_of_leafs: Array1<i64>,
-    pub particle_list_of_leafs_scan: Array1<i64>,
-    pub splines: Array3<f64>,
-    pub densities: Array2<f64>,
-    pub quantiles: Array1<f64>,
-    pub coordinates: Array2<f64>,
-    pub voronoi_diameter_extended: Array1<f64>,
+    pub particle_list_of_leafs: NpyArray<i64, Ix1>,
+    pub particle_list_of_leafs_scan: NpyArray<i64, Ix1>,
+    pub splines: NpyArray<f64, Ix3>,
+    pub densities: NpyArray<f64, Ix2>,
+    pub quantiles: NpyArray<f64, Ix1>,
+    pub coordinates: NpyArray<f64, Ix2>,
+    pub voronoi_diameter_extended: NpyArray<f64, Ix1>,
     pub octree: SharedPtr<Octree>,
+    /// Local directory the entry was loaded from, watched for changes while the entry is cached.
+    pub snapdir: Option<PathBuf>,
+    pub fingerprint: FileFingerprint,
+    /// Side length of the periodic simulation box, `None` if the header could not be read.
+    pub box_size: Option<f64>,
+    /// Fields whose particle arrays are loaded, the arrays of the others are empty.
+    pub fields: Vec<LodField>,
+    /// Further per-particle quantities found in the snapdir, by name.
+    pub particle_fields: HashMap<String, ParticleField>,
+    /// ParticleIDs in the order of the particle arrays, empty if the source has none.
+    pub particle_ids: NpyArray<u64, Ix1>,
 }
 
-pub struct DataCache {
-    pub rand: isize,
-    pub cache: HashMap<CacheRequest, Arc<CacheEntry>>,
-    pub basedir: String,
+impl CacheEntry {
+    /// Memory held by the particle arrays, including the mapped length of memory mapped arrays.
+    /// The octree is small in comparison and not accounted for.
+    pub fn size_in_bytes(&self) -> usize {
+        self.particle_list_of_leafs.size_in_bytes()
+            + self.particle_list_of_leafs_scan.size_in_bytes()
+            + self.splines.size_in_bytes()
+            + self.densities.size_in_bytes()
+            + self.quantiles.size_in_bytes()
+            + self.coordinates.size_in_bytes()
+            + self.voronoi_diameter_extended.size_in_bytes()
+            + self.particle_ids.size_in_bytes()
+            + self
+                .particle_fields
+                .values()
+                .map(|field| field.size_in_bytes())
+                .sum::<usize>()
+    }
+}
+
+/// A cached entry together with the bookkeeping needed for eviction.
+pub struct CacheSlot {
+    pub entry: Arc<CacheEntry>,
+    pub size_in_bytes: usize,
+    pub last_access: u64,
+    pub n_accesses: u64,
+    /// Loaded by the prefetcher and not requested by a client yet.
+    pub prefetched: bool,
+}
+
+/// Keeps the metadata server informed about which snapshots this node serves.
+#[derive(Clone)]
+pub struct MetadataClient {
     pub metadata_url: String,
     pub hostname: String,
 }
 
-impl DataCache {
-    pub fn new(basedir: String, metadata_url: String, hostname: String) -> Self {
-        DataCache {
-            rand: random(),
-            cache: HashMap::new(),
-            basedir,
-            metadata_url,
-            hostname,
-        }
-    }
-
+impl MetadataClient {
     pub fn send_info_about_cache_loading(
         &self,
         request: &CacheRequest,
@@ -80,85 +157,552 @@ impl DataCache {
             .send_json(request)?)
     }
 
-    pub fn load_entry(&mut self, request: &CacheRequest) -> anyhow::Result<Arc<CacheEntry>> {
-        /*
-        log::info!("Starting async thing.");
-        let other: &Self = self;
-        let async_request = request.clone();
-        actix_web::rt::spawn(async move {
-            log::info!("Within async");
-            other.send_info_about_cache_loading(&async_request)
-                .await
+    pub fn send_info_about_cache_eviction(
+        &self,
+        request: &CacheRequest,
+    ) -> Result<ureq::Response, ureq::Error> {
+        Ok(ureq::post(&(self.metadata_url.clone() + "/del_snap"))
+            .set("User-Agent", &self.hostname)
+            .send_json(request)?)
+    }
+
+    /// Send the eviction info on the blocking thread pool without waiting for it, a slow
+    /// metadata server must not stall the cache actor.
+    pub fn notify_eviction(&self, request: &CacheRequest) {
+        let metadata = self.clone();
+        let request = request.clone();
+        spawn_blocking(move || {
+            let _ = metadata
+                .send_info_about_cache_eviction(&request)
                 .inspect_err(|err| {
                     log::warn!(
-                        "failed to send info about loading cache to metadata server: {:?}",
+                        "failed to send info about cache eviction to metadata server: {:?}",
                         err
                     )
-                })
-                .unwrap();
-        });
-        */
-        let basedir = self.basedir.clone()
-            + "/"
-            + &request.simulation
-            + "/"
-            + &format!("snapdir_{:03}", request.snapshot_id)
-            + "/";
-
-        let particle_list_of_leafs =
-            read_npy(basedir.clone() + "particle_list_of_leafs_Density.npy")
-                .context("Failed to open particle_list_of_leafs")?;
-        let particle_list_of_leafs_scan =
-            read_npy(basedir.clone() + "particle_list_of_leafs_Density_scan.npy")
-                .context("Failed to open particle_list_of_leafs_scan")?;
-        let splines =
-            read_npy(basedir.clone() + "splines.npy").context("Failed to open splines")?;
-        let densities: Array2<f64> =
-            read_npy(basedir.clone() + "Density.npy").context("Failed to open Density")?;
-        let quantiles: Array1<f64> = read_npy(basedir.clone() + "densities_quantiles.npy")
-            .context("Failed to open density_quantiles")?;
-        let coordinates =
-            read_npy(basedir.clone() + "Coordinates.npy").context("Failed to open Coordinates")?;
-        let voronoi_diameter_extended = read_npy(basedir.clone() + "voronoi_diameter_extended.npy")
-            .context("Failed to open voronoi_diameter_extended")?;
-
-        let octree = load_octree_from_file(basedir.clone() + "o3dOctree.json");
-
-        let entry = Arc::new(CacheEntry {
-            particle_list_of_leafs,
-            particle_list_of_leafs_scan,
-            splines,
-            densities,
-            quantiles,
-            coordinates,
-            voronoi_diameter_extended,
-            octree,
+                });
         });
+    }
+}
+
+/// How long a listing of the snapshots of a simulation is used for prefetching, snapdirs
+/// that are added later are picked up after that.
+const SNAPSHOT_IDS_TTL: Duration = Duration::from_secs(60);
+
+type InFlightLoad = Shared<BoxFuture<'static, Result<Arc<CacheEntry>, Arc<anyhow::Error>>>>;
+
+pub struct DataCache {
+    pub rand: isize,
+    pub cache: HashMap<CacheRequest, CacheSlot>,
+    /// Loads that are currently running on the blocking thread pool.
+    pub in_flight: HashMap<CacheRequest, InFlightLoad>,
+    /// In-flight loads that were started by the prefetcher and not requested by a client yet.
+    pub in_flight_prefetches: HashSet<CacheRequest>,
+    /// In-flight loads that were evicted, their result is not cached.
+    pub cancelled_loads: HashSet<CacheRequest>,
+    /// Snapshot ids found per simulation, sorted ascending, with the time they were listed.
+    pub snapshot_ids: HashMap<String, (Instant, Vec<usize>)>,
+    /// Sources of the simulations configured under `sources`.
+    pub sources: HashMap<String, Arc<dyn SnapshotSource>>,
+    /// Source of all other simulations.
+    pub default_source: Arc<dyn SnapshotSource>,
+    /// Fields loaded per simulation, everything for simulations that are not listed.
+    pub loaded_fields: HashMap<String, Vec<LoadedField>>,
+    pub metadata: MetadataClient,
+    pub memory_budget: Option<usize>,
+    pub eviction_policy: EvictionPolicy,
+    pub prefetch: PrefetchConfig,
+    pub used_memory: usize,
+    access_counter: u64,
+    pub on_file_change: FileChangePolicy,
+    pub file_change_delay: Duration,
+    watcher: Option<RecommendedWatcher>,
+    /// Entries with change events on their snapdir that still need to be checked.
+    changed: HashSet<CacheRequest>,
+    change_check_scheduled: bool,
+}
+
+impl DataCache {
+    pub fn new(cfg: &WebServiceConfig, memory_budget: Option<usize>) -> anyhow::Result<Self> {
+        Ok(DataCache {
+            rand: random(),
+            cache: HashMap::new(),
+            in_flight: HashMap::new(),
+            in_flight_prefetches: HashSet::new(),
+            cancelled_loads: HashSet::new(),
+            snapshot_ids: HashMap::new(),
+            sources: cfg
+                .sources
+                .iter()
+                .map(|(simulation, source)| {
+                    let source = source::from_config(cfg, source)
+                        .with_context(|| format!("Invalid source of {}", simulation))?;
+                    Ok((simulation.clone(), source))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            default_source: Arc::new(NpyDirSource {
+                basedir: cfg.basedir.clone(),
+                npy_load_mode: cfg.npy_load_mode,
+            }),
+            loaded_fields: cfg.loaded_fields.clone(),
+            metadata: MetadataClient {
+                metadata_url: cfg.metadata_url.clone(),
+                hostname: cfg.cache_server_url.clone(),
+            },
+            memory_budget,
+            eviction_policy: cfg.eviction_policy,
+            prefetch: cfg.prefetch.clone(),
+            used_memory: 0,
+            access_counter: 0,
+            on_file_change: cfg.on_file_change,
+            file_change_delay: Duration::from_secs(cfg.file_change_delay_secs),
+            watcher: None,
+            changed: HashSet::new(),
+            change_check_scheduled: false,
+        })
+    }
+
+    /// Look up an entry and record the access for the eviction policy.
+    pub fn get_entry(&mut self, request: &CacheRequest) -> Option<Arc<CacheEntry>> {
+        self.access_counter += 1;
+        let access = self.access_counter;
+        self.cache.get_mut(request).map(|slot| {
+            slot.last_access = access;
+            slot.n_accesses += 1;
+            slot.prefetched = false;
+            slot.entry.clone()
+        })
+    }
+
+    pub fn peek_entry(&self, request: &CacheRequest) -> Option<Arc<CacheEntry>> {
+        self.cache.get(request).map(|slot| slot.entry.clone())
+    }
+
+    pub fn insert_entry(
+        &mut self,
+        request: CacheRequest,
+        entry: Arc<CacheEntry>,
+        prefetched: bool,
+    ) {
+        self.access_counter += 1;
+        let size_in_bytes = entry.size_in_bytes();
+        let slot = CacheSlot {
+            entry,
+            size_in_bytes,
+            last_access: self.access_counter,
+            n_accesses: if prefetched { 0 } else { 1 },
+            prefetched,
+        };
+        let snapdir = slot.entry.snapdir.clone();
+        self.used_memory += size_in_bytes;
+        if let Some(old) = self.cache.insert(request.clone(), slot) {
+            self.used_memory -= old.size_in_bytes;
+        }
+        if let (Some(watcher), Some(snapdir)) = (&mut self.watcher, snapdir) {
+            let _ = watcher
+                .watch(&snapdir, RecursiveMode::NonRecursive)
+                .inspect_err(|err| log::warn!("failed to watch {:?}: {:?}", snapdir, err));
+        }
+        if prefetched {
+            self.fit_prefetched(&request);
+        } else {
+            self.evict_to_fit(&request);
+        }
+    }
+
+    /// Make room for a prefetched entry. Prefetches only use free memory and may only displace
+    /// other prefetched entries, if that does not suffice the entry itself is dropped again.
+    pub fn fit_prefetched(&mut self, request: &CacheRequest) {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        while self.used_memory > budget {
+            let victim = self
+                .select_victim(request)
+                .filter(|victim| self.cache[victim].prefetched);
+            match victim {
+                Some(victim) => {
+                    self.evict(&victim);
+                }
+                None => {
+                    log::debug!(
+                        "dropping prefetched {} snapdir_{:03}, memory budget is exhausted",
+                        request.simulation,
+                        request.snapshot_id
+                    );
+                    self.evict(request);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Pick the entry that should be dropped next according to the eviction policy. Prefetched
+    /// entries that no client asked for yet go first.
+    pub fn select_victim(&self, keep: &CacheRequest) -> Option<CacheRequest> {
+        let candidates = self.cache.iter().filter(|(request, _)| *request != keep);
+        let victim = match self.eviction_policy {
+            EvictionPolicy::Lru => {
+                candidates.min_by_key(|(_, slot)| (!slot.prefetched, slot.last_access))
+            }
+            EvictionPolicy::Lfu => candidates
+                .min_by_key(|(_, slot)| (!slot.prefetched, slot.n_accesses, slot.last_access)),
+        };
+        victim.map(|(request, _)| request.clone())
+    }
+
+    /// Remove an entry and tell the metadata server that we no longer serve it.
+    pub fn evict(&mut self, request: &CacheRequest) -> Option<Arc<CacheEntry>> {
+        let slot = self.cache.remove(request)?;
+        self.used_memory -= slot.size_in_bytes;
+        if let (Some(watcher), Some(snapdir)) = (&mut self.watcher, &slot.entry.snapdir) {
+            let _ = watcher.unwatch(snapdir);
+        }
+        log::info!(
+            "evicted {} snapdir_{:03} ({} MiB), {} MiB in use",
+            request.simulation,
+            request.snapshot_id,
+            slot.size_in_bytes / (1024 * 1024),
+            self.used_memory / (1024 * 1024)
+        );
+        self.metadata.notify_eviction(request);
+        Some(slot.entry)
+    }
+
+    /// Evict entries until the used memory fits into the budget. `keep` is never evicted.
+    pub fn evict_to_fit(&mut self, keep: &CacheRequest) {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        while self.used_memory > budget {
+            match self.select_victim(keep) {
+                Some(victim) => {
+                    self.evict(&victim);
+                }
+                None => {
+                    log::warn!(
+                        "{} snapdir_{:03} alone exceeds the memory budget of {} MiB",
+                        keep.simulation,
+                        keep.snapshot_id,
+                        budget / (1024 * 1024)
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Start loading an entry on the blocking thread pool. Concurrent calls for the same
+    /// request share a single load.
+    pub fn start_loading(
+        &mut self,
+        request: CacheRequest,
+        prefetch: bool,
+        ctx: &mut actix::Context<Self>,
+    ) -> InFlightLoad {
+        if let Some(load) = self.in_flight.get(&request) {
+            if !prefetch {
+                self.in_flight_prefetches.remove(&request);
+                // Requested again after it was evicted, cache it after all.
+                self.cancelled_loads.remove(&request);
+            }
+            return load.clone();
+        }
+        if prefetch {
+            self.in_flight_prefetches.insert(request.clone());
+        }
+
+        let source = self.source(&request.simulation);
+        let fields = self.loaded_fields(&request.simulation);
+        let metadata = self.metadata.clone();
+        let blocking_request = request.clone();
+        let load = async move {
+            spawn_blocking(move || {
+                let _ = metadata
+                    .send_info_about_cache_loading(&blocking_request)
+                    .inspect_err(|err| {
+                        log::warn!(
+                            "failed to send info about loading cache to metadata server: {:?}",
+                            err
+                        )
+                    });
+                source.load(&blocking_request, &fields)
+                    .map(|mut entry| {
+                        entry.box_size = source
+                            .box_size(&blocking_request.simulation, blocking_request.snapshot_id)
+                            .inspect_err(|err| {
+                                log::warn!(
+                                    "failed to read box size, periodic boundaries are ignored {:?}",
+                                    err
+                                )
+                            })
+                            .ok()
+                            .map(|box_size| box_size as f64);
+                        entry
+                    })
+                    .map(Arc::new)
+                    .inspect_err(|err| {
+                        log::warn!("failed to load entry {:?}", err);
+                        let _ = metadata
+                            .send_info_about_cache_loading_fail(&blocking_request)
+                            .inspect_err(|err| {
+                                log::warn!(
+                                    "failed to send info about loading cache to metadata server: {:?}",
+                                    err
+                                )
+                            });
+                    })
+            })
+            .await
+            .map_err(|err| anyhow!("Loading task failed: {:?}", err))
+            .and_then(|result| result)
+            .map_err(Arc::new)
+        }
+        .boxed()
+        .shared();
+
+        self.in_flight.insert(request.clone(), load.clone());
+        ctx.spawn(load.clone().into_actor(self).map(move |result, act, ctx| {
+            let prefetched = act.in_flight_prefetches.contains(&request);
+            match result {
+                Ok(entry) => {
+                    let size_in_bytes = entry.size_in_bytes();
+                    if act.finish_loading(&request, entry) && !prefetched {
+                        act.prefetch_neighbours(&request, size_in_bytes, ctx);
+                    }
+                }
+                Err(_) => act.fail_loading(&request),
+            }
+        }));
+        load
+    }
+
+    /// Cache the result of an in-flight load unless it was cancelled in the meantime. Returns
+    /// whether the entry was cached.
+    pub fn finish_loading(&mut self, request: &CacheRequest, entry: Arc<CacheEntry>) -> bool {
+        self.in_flight.remove(request);
+        let prefetched = self.in_flight_prefetches.remove(request);
+        if self.cancelled_loads.remove(request) {
+            log::info!(
+                "discarding {} snapdir_{:03}, it was evicted while loading",
+                request.simulation,
+                request.snapshot_id
+            );
+            self.metadata.notify_eviction(request);
+            return false;
+        }
+        self.insert_entry(request.clone(), entry, prefetched);
+        true
+    }
+
+    /// Clean up after a failed load. A failed reload keeps serving the entry it should have
+    /// replaced, only entries of the `Invalidate` policy are dropped.
+    pub fn fail_loading(&mut self, request: &CacheRequest) {
+        self.in_flight.remove(request);
+        self.in_flight_prefetches.remove(request);
+        self.cancelled_loads.remove(request);
+        if self.on_file_change == FileChangePolicy::Invalidate {
+            self.evict(request);
+        } else if self.cache.contains_key(request) {
+            log::warn!(
+                "reloading {} snapdir_{:03} failed, serving the previous entry",
+                request.simulation,
+                request.snapshot_id
+            );
+        }
+    }
+
+    /// Evict an entry and cancel a load of it that is still running. Returns whether there was
+    /// anything to evict.
+    pub fn evict_or_cancel(&mut self, request: &CacheRequest) -> bool {
+        let loading = self.in_flight.contains_key(request);
+        if loading {
+            self.cancelled_loads.insert(request.clone());
+        }
+        self.evict(request).is_some() || loading
+    }
+
+    pub fn source(&self, simulation: &str) -> Arc<dyn SnapshotSource> {
+        self.sources
+            .get(simulation)
+            .unwrap_or(&self.default_source)
+            .clone()
+    }
 
-        self.cache.insert(request.clone(), entry.clone());
-        Ok(entry)
+    pub fn loaded_fields(&self, simulation: &str) -> LoadedFields {
+        self.loaded_fields
+            .get(simulation)
+            .map(|entries| LoadedFields::from_config(entries))
+            .unwrap_or_else(LoadedFields::all)
     }
 
-    pub fn cached_entries(&self) -> anyhow::Result<HashMap<String, Vec<usize>>> {
-        let mut cached_entries = HashMap::new();
-        for key in self.cache.keys() {
-            let simulation = &key.simulation;
-            let snapshot_id = &key.snapshot_id;
-            if !cached_entries.contains_key(simulation) {
-                cached_entries.insert(simulation.clone(), vec![]);
+    /// Schedule background loads of the snapshots next to `request`, as long as they fit into
+    /// the memory budget. Neighbours are assumed to be roughly as large as `size_in_bytes`.
+    ///
+    /// The snapshots of the simulation are listed on the blocking thread pool, a listing is
+    /// reused for `SNAPSHOT_IDS_TTL`.
+    pub fn prefetch_neighbours(
+        &mut self,
+        request: &CacheRequest,
+        size_in_bytes: usize,
+        ctx: &mut actix::Context<Self>,
+    ) {
+        if self.prefetch.depth_for(&request.simulation) == 0 {
+            return;
+        }
+        if let Some((listed, snapshot_ids)) = self.snapshot_ids.get(&request.simulation) {
+            if listed.elapsed() < SNAPSHOT_IDS_TTL {
+                let snapshot_ids = snapshot_ids.clone();
+                self.prefetch_candidates(request, &snapshot_ids, size_in_bytes, ctx);
+                return;
+            }
+        }
+
+        let source = self.source(&request.simulation);
+        let simulation = request.simulation.clone();
+        let request = request.clone();
+        let listing = async move {
+            spawn_blocking(move || source.snapshot_ids(&simulation))
+                .await
+                .map_err(|err| anyhow!("Listing task failed: {:?}", err))
+                .and_then(|result| result)
+        };
+        ctx.spawn(
+            listing
+                .into_actor(self)
+                .map(move |result, act, ctx| match result {
+                    Ok(snapshot_ids) => {
+                        act.snapshot_ids.insert(
+                            request.simulation.clone(),
+                            (Instant::now(), snapshot_ids.clone()),
+                        );
+                        act.prefetch_candidates(&request, &snapshot_ids, size_in_bytes, ctx);
+                    }
+                    Err(err) => log::warn!("failed to list snapshots for prefetching: {:?}", err),
+                }),
+        );
+    }
+
+    /// Start the prefetches of `prefetch_neighbours` once the snapshots are listed.
+    fn prefetch_candidates(
+        &mut self,
+        request: &CacheRequest,
+        snapshot_ids: &[usize],
+        size_in_bytes: usize,
+        ctx: &mut actix::Context<Self>,
+    ) {
+        let depth = self.prefetch.depth_for(&request.simulation);
+        let position = match snapshot_ids.binary_search(&request.snapshot_id) {
+            Ok(position) => position,
+            Err(_) => return,
+        };
+
+        // Closest neighbours first, the next snapshot before the previous one.
+        let mut candidates = vec![];
+        for distance in 1..=depth {
+            if let Some(snapshot_id) = snapshot_ids.get(position + distance) {
+                candidates.push(*snapshot_id);
+            }
+            if let Some(snapshot_id) = position
+                .checked_sub(distance)
+                .and_then(|position| snapshot_ids.get(position))
+            {
+                candidates.push(*snapshot_id);
+            }
+        }
+
+        for snapshot_id in candidates {
+            let neighbour = CacheRequest {
+                simulation: request.simulation.clone(),
+                snapshot_id,
+            };
+            if self.cache.contains_key(&neighbour) || self.in_flight.contains_key(&neighbour) {
+                continue;
+            }
+            if let Some(budget) = self.memory_budget {
+                let pending = self.in_flight_prefetches.len() * size_in_bytes;
+                if self.used_memory + pending + size_in_bytes > budget {
+                    log::debug!("skipping prefetches, memory budget is exhausted");
+                    break;
+                }
             }
+            log::info!(
+                "prefetching {} snapdir_{:03}",
+                neighbour.simulation,
+                neighbour.snapshot_id
+            );
+            // The load is driven by the actor, nobody waits for the result.
+            let _ = self.start_loading(neighbour, true, ctx);
+        }
+    }
 
-            let snapshot_ids = cached_entries
-                .get_mut(simulation)
-                .context("Hashmap should contain vec for simulation as we just inserted it")?;
-            snapshot_ids.push(*snapshot_id);
+    /// Re-check the fingerprints of entries whose snapdir reported changes and drop or reload
+    /// the ones whose input files differ from the loaded state.
+    pub fn check_changed_entries(&mut self, ctx: &mut actix::Context<Self>) {
+        self.change_check_scheduled = false;
+        let changed: Vec<CacheRequest> = self.changed.drain().collect();
+        for request in changed {
+            let stale = match self.cache.get(&request) {
+                Some(slot) => slot.entry.fingerprint.is_stale(),
+                None => continue,
+            };
+            if !stale || self.in_flight.contains_key(&request) {
+                continue;
+            }
+            log::info!(
+                "input files of {} snapdir_{:03} changed on disk",
+                request.simulation,
+                request.snapshot_id
+            );
+            match self.on_file_change {
+                FileChangePolicy::Ignore => {}
+                FileChangePolicy::Invalidate => {
+                    self.evict(&request);
+                }
+                FileChangePolicy::Reload => {
+                    // The stale entry keeps being served until the reload replaces it.
+                    let _ = self.start_loading(request, false, ctx);
+                }
+            }
         }
-        Ok(cached_entries)
+    }
+
+    pub fn cached_entries(&self) -> anyhow::Result<CurrentCacheResponse> {
+        let mut response = CurrentCacheResponse {
+            cached: HashMap::new(),
+            prefetched: HashMap::new(),
+        };
+        for (key, slot) in &self.cache {
+            let entries = if slot.prefetched {
+                &mut response.prefetched
+            } else {
+                &mut response.cached
+            };
+            entries
+                .entry(key.simulation.clone())
+                .or_insert_with(Vec::new)
+                .push(key.snapshot_id);
+        }
+        Ok(response)
     }
 }
 
 impl Actor for DataCache {
     type Context = actix::Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.on_file_change == FileChangePolicy::Ignore {
+            return;
+        }
+        match file_watcher(ctx.address().recipient()) {
+            Ok(watcher) => self.watcher = Some(watcher),
+            Err(err) => log::warn!(
+                "failed to create file watcher, changes on disk are not detected: {:?}",
+                err
+            ),
+        }
+    }
 }
 
 impl Handler<RandU> for DataCache {
@@ -169,51 +713,328 @@ impl Handler<RandU> for DataCache {
     }
 }
 
-impl Handler<BaseDirRequest> for DataCache {
-    type Result = String;
+impl Handler<SnapshotSourceRequest> for DataCache {
+    type Result = MessageResult<SnapshotSourceRequest>;
 
-    fn handle(&mut self, _msg: BaseDirRequest, _ctx: &mut actix::Context<Self>) -> Self::Result {
-        self.basedir.clone()
+    fn handle(
+        &mut self,
+        msg: SnapshotSourceRequest,
+        _ctx: &mut actix::Context<Self>,
+    ) -> Self::Result {
+        MessageResult(self.source(&msg.simulation))
     }
 }
 
 impl Handler<CacheRequest> for DataCache {
-    type Result = anyhow::Result<Arc<CacheEntry>>;
+    type Result = ResponseFuture<anyhow::Result<Arc<CacheEntry>>>;
 
-    fn handle(&mut self, msg: CacheRequest, _ctx: &mut actix::Context<Self>) -> Self::Result {
-        match self.cache.get(&msg) {
-            Some(entry) => Ok(entry.clone()),
+    fn handle(&mut self, msg: CacheRequest, ctx: &mut actix::Context<Self>) -> Self::Result {
+        match self.get_entry(&msg) {
+            Some(entry) => {
+                self.prefetch_neighbours(&msg, entry.size_in_bytes(), ctx);
+                Box::pin(async move { Ok(entry) })
+            }
             _ => {
-                self.send_info_about_cache_loading(&msg)
-                    .inspect_err(|err| {
-                        log::warn!(
-                            "failed to send info about loading cache to metadata server: {:?}",
-                            err
-                        )
-                    })
-                    .unwrap();
-                match self.load_entry(&msg) {
-                    Ok(result) => return Ok(result),
-                    Err(err) => {
-                        log::warn!("failed to calculate load_entry {:?}", err);
-                        self.send_info_about_cache_loading_fail(&msg).inspect_err(|err| log::warn!("failed to send info about loading cache to metadata server: {:?}", err)).unwrap();
-                        Err(err)
-                    }
-                }
+                let load = self.start_loading(msg, false, ctx);
+                Box::pin(async move { load.await.map_err(|err| anyhow!("{:?}", err)) })
             }
         }
     }
 }
 
+impl Handler<PeekRequest> for DataCache {
+    type Result = Option<Arc<CacheEntry>>;
+
+    fn handle(&mut self, msg: PeekRequest, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        self.peek_entry(&CacheRequest {
+            simulation: msg.simulation,
+            snapshot_id: msg.snapshot_id,
+        })
+    }
+}
+
 impl Handler<CachedEntriesRequest> for DataCache {
-    type Result = Arc<anyhow::Result<HashMap<String, Vec<usize>>>>;
+    type Result = Arc<anyhow::Result<CurrentCacheResponse>>;
 
     fn handle(
         &mut self,
         _msg: CachedEntriesRequest,
         _ctx: &mut actix::Context<Self>,
     ) -> Self::Result {
-        let hashmap: anyhow::Result<HashMap<String, Vec<usize>>> = self.cached_entries();
-        return Arc::new(hashmap);
+        let response: anyhow::Result<CurrentCacheResponse> = self.cached_entries();
+        return Arc::new(response);
+    }
+}
+
+impl Handler<LoadRequest> for DataCache {
+    type Result = ResponseFuture<anyhow::Result<usize>>;
+
+    fn handle(&mut self, msg: LoadRequest, ctx: &mut actix::Context<Self>) -> Self::Result {
+        let request = CacheRequest {
+            simulation: msg.simulation,
+            snapshot_id: msg.snapshot_id,
+        };
+        match self.get_entry(&request) {
+            Some(entry) => Box::pin(async move { Ok(entry.size_in_bytes()) }),
+            _ => {
+                let load = self.start_loading(request, false, ctx);
+                Box::pin(async move {
+                    load.await
+                        .map(|entry| entry.size_in_bytes())
+                        .map_err(|err| anyhow!("{:?}", err))
+                })
+            }
+        }
+    }
+}
+
+impl Handler<EvictRequest> for DataCache {
+    type Result = bool;
+
+    fn handle(&mut self, msg: EvictRequest, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        let request = CacheRequest {
+            simulation: msg.simulation,
+            snapshot_id: msg.snapshot_id,
+        };
+        self.evict_or_cancel(&request)
+    }
+}
+
+impl Handler<ClearCacheRequest> for DataCache {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: ClearCacheRequest, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        let loading: Vec<CacheRequest> = self.in_flight.keys().cloned().collect();
+        self.cancelled_loads.extend(loading);
+        let cached: Vec<CacheRequest> = self.cache.keys().cloned().collect();
+        let mut n_evicted = 0;
+        for request in &cached {
+            if self.evict(request).is_some() {
+                n_evicted += 1;
+            }
+        }
+        n_evicted
+    }
+}
+
+impl Handler<FilesChanged> for DataCache {
+    type Result = ();
+
+    fn handle(&mut self, msg: FilesChanged, ctx: &mut actix::Context<Self>) -> Self::Result {
+        for path in &msg.paths {
+            let snapdir = if path.is_dir() {
+                path.as_path()
+            } else {
+                match path.parent() {
+                    Some(parent) => parent,
+                    None => continue,
+                }
+            };
+            // Entries can depend on files outside their snapdir, like the next snapshot of an
+            // HDF5 source, those are noticed while that snapdir is watched.
+            for (request, slot) in &self.cache {
+                if slot.entry.snapdir.as_deref() == Some(snapdir)
+                    || slot
+                        .entry
+                        .fingerprint
+                        .files
+                        .iter()
+                        .any(|(file, _, _)| file == path)
+                {
+                    self.changed.insert(request.clone());
+                }
+            }
+        }
+
+        // Preprocessing writes several files, wait for it to settle before checking.
+        if !self.changed.is_empty() && !self.change_check_scheduled {
+            self.change_check_scheduled = true;
+            ctx.run_later(self.file_change_delay, |act, ctx| {
+                act.check_changed_entries(ctx)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Array1, Array2, Array3};
+
+    fn entry(n_particles: usize) -> Arc<CacheEntry> {
+        Arc::new(CacheEntry {
+            particle_list_of_leafs: Array1::zeros(n_particles).into(),
+            particle_list_of_leafs_scan: Array1::zeros(1).into(),
+            splines: Array3::zeros((n_particles, 4, 3)).into(),
+            densities: Array2::zeros((2, n_particles)).into(),
+            quantiles: Array1::zeros(10).into(),
+            coordinates: Array2::zeros((n_particles, 3)).into(),
+            voronoi_diameter_extended: Array1::zeros(n_particles).into(),
+            octree: SharedPtr::null(),
+            snapdir: None,
+            fingerprint: FileFingerprint::default(),
+            box_size: None,
+            fields: LodField::ALL.to_vec(),
+            particle_fields: HashMap::new(),
+            particle_ids: Array1::zeros(0).into(),
+        })
+    }
+
+    fn request(snapshot_id: usize) -> CacheRequest {
+        CacheRequest {
+            simulation: "TNG50-4".to_string(),
+            snapshot_id,
+        }
+    }
+
+    fn cache(memory_budget: Option<usize>, eviction_policy: EvictionPolicy) -> DataCache {
+        let cfg = WebServiceConfig {
+            basedir: "/nonexistent".to_string(),
+            metadata_url: "http://localhost:0".to_string(),
+            eviction_policy,
+            ..Default::default()
+        };
+        DataCache::new(&cfg, memory_budget).unwrap()
+    }
+
+    #[test]
+    fn test_size_in_bytes() {
+        // 8 byte elements: 100 + 1 + 1200 + 200 + 10 + 300 + 100
+        assert_eq!(entry(100).size_in_bytes(), 1911 * 8);
+    }
+
+    #[actix_web::test]
+    async fn test_lru_eviction() {
+        let size = entry(100).size_in_bytes();
+        let mut cache = cache(Some(2 * size), EvictionPolicy::Lru);
+        cache.insert_entry(request(1), entry(100), false);
+        cache.insert_entry(request(2), entry(100), false);
+        assert!(cache.get_entry(&request(1)).is_some());
+        cache.insert_entry(request(3), entry(100), false);
+
+        assert!(cache.cache.contains_key(&request(1)));
+        assert!(!cache.cache.contains_key(&request(2)));
+        assert!(cache.cache.contains_key(&request(3)));
+        assert_eq!(cache.used_memory, 2 * size);
+    }
+
+    #[actix_web::test]
+    async fn test_lfu_eviction() {
+        let size = entry(100).size_in_bytes();
+        let mut cache = cache(Some(2 * size), EvictionPolicy::Lfu);
+        cache.insert_entry(request(1), entry(100), false);
+        cache.get_entry(&request(1));
+        cache.get_entry(&request(1));
+        cache.insert_entry(request(2), entry(100), false);
+        cache.get_entry(&request(2));
+        cache.get_entry(&request(1));
+        cache.insert_entry(request(3), entry(100), false);
+
+        assert!(cache.cache.contains_key(&request(1)));
+        assert!(!cache.cache.contains_key(&request(2)));
+        assert!(cache.cache.contains_key(&request(3)));
+    }
+
+    #[actix_web::test]
+    async fn test_oversized_entry_is_kept() {
+        let mut cache = cache(Some(1), EvictionPolicy::Lru);
+        cache.insert_entry(request(1), entry(100), false);
+        cache.insert_entry(request(2), entry(100), false);
+
+        assert_eq!(cache.cache.len(), 1);
+        assert!(cache.cache.contains_key(&request(2)));
+    }
+
+    #[actix_web::test]
+    async fn test_prefetched_entries_are_evicted_first() {
+        let size = entry(100).size_in_bytes();
+        let mut cache = cache(Some(2 * size), EvictionPolicy::Lru);
+        cache.insert_entry(request(1), entry(100), false);
+        cache.insert_entry(request(2), entry(100), true);
+        cache.insert_entry(request(3), entry(100), false);
+
+        assert!(cache.cache.contains_key(&request(1)));
+        assert!(!cache.cache.contains_key(&request(2)));
+
+        let current = cache.cached_entries().unwrap();
+        assert_eq!(current.prefetched.len(), 0);
+        assert_eq!(current.cached["TNG50-4"].len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_prefetch_does_not_evict_requested_entries() {
+        let size = entry(100).size_in_bytes();
+        let mut cache = cache(Some(2 * size), EvictionPolicy::Lru);
+        cache.insert_entry(request(1), entry(100), false);
+        cache.insert_entry(request(2), entry(100), true);
+        // Only the other prefetched entry makes room.
+        cache.insert_entry(request(3), entry(100), true);
+        assert!(cache.cache.contains_key(&request(1)));
+        assert!(!cache.cache.contains_key(&request(2)));
+        assert!(cache.cache.contains_key(&request(3)));
+
+        cache.get_entry(&request(3));
+        cache.insert_entry(request(4), entry(100), true);
+        assert!(cache.cache.contains_key(&request(1)));
+        assert!(cache.cache.contains_key(&request(3)));
+        assert!(!cache.cache.contains_key(&request(4)));
+        assert_eq!(cache.used_memory, 2 * size);
+    }
+
+    #[actix_web::test]
+    async fn test_evicted_load_is_not_cached() {
+        let mut cache = cache(None, EvictionPolicy::Lru);
+        let load = futures::future::pending().boxed().shared();
+        cache.in_flight.insert(request(1), load.clone());
+        cache.in_flight.insert(request(2), load);
+
+        assert!(cache.evict_or_cancel(&request(1)));
+        assert!(!cache.evict_or_cancel(&request(3)));
+        assert!(!cache.finish_loading(&request(1), entry(100)));
+        assert!(cache.finish_loading(&request(2), entry(100)));
+
+        assert!(!cache.cache.contains_key(&request(1)));
+        assert!(cache.cache.contains_key(&request(2)));
+        assert!(cache.in_flight.is_empty() && cache.cancelled_loads.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_clear_cache_counts_evicted_entries() {
+        let mut cache = cache(None, EvictionPolicy::Lru);
+        cache.insert_entry(request(1), entry(100), false);
+        cache.insert_entry(request(2), entry(100), false);
+        cache
+            .in_flight
+            .insert(request(3), futures::future::pending().boxed().shared());
+        let cache = cache.start();
+
+        assert_eq!(cache.send(ClearCacheRequest).await.unwrap(), 2);
+        assert_eq!(cache.send(ClearCacheRequest).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_peek_does_not_count_as_access() {
+        let mut cache = cache(None, EvictionPolicy::Lru);
+        cache.insert_entry(request(1), entry(100), false);
+        let access_counter = cache.access_counter;
+
+        assert!(cache.peek_entry(&request(1)).is_some());
+        assert!(cache.peek_entry(&request(2)).is_none());
+        assert_eq!(cache.access_counter, access_counter);
+        assert_eq!(cache.cache[&request(1)].n_accesses, 1);
+        assert!(cache.in_flight.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_failed_reload_keeps_entry() {
+        let mut cache = cache(None, EvictionPolicy::Lru);
+        cache.on_file_change = FileChangePolicy::Reload;
+        cache.insert_entry(request(1), entry(100), false);
+        cache.fail_loading(&request(1));
+        assert!(cache.cache.contains_key(&request(1)));
+
+        cache.on_file_change = FileChangePolicy::Invalidate;
+        cache.fail_loading(&request(1));
+        assert!(!cache.cache.contains_key(&request(1)));
     }
 }
@@ -1,14 +1,62 @@
-use super::bind::ffi::{RustVec3, Viewbox};
+use super::bind::ffi::{Frustum, NodeInfo, Plane, RustVec3, Viewbox};
 
+use super::utils::total_memory_bytes;
+
+use anyhow::anyhow;
+use ndarray::ArrayView1;
 use serde::{Deserialize, Serialize};
+use std::cmp::min;
 use std::collections::HashMap;
 
-#[derive(Deserialize, Clone)]
+/// Camera of a client. `x`, `y`, `z` is the position. Without the perspective fields the view
+/// is the axis aligned cube of side `size` around the position.
+#[derive(Deserialize, Clone, Default)]
 pub struct CameraInfo {
     pub x: f64,
     pub y: f64,
     pub z: f64,
     pub size: f64,
+    /// Viewing direction.
+    #[serde(default)]
+    pub direction: Option<[f64; 3]>,
+    #[serde(default)]
+    pub up: Option<[f64; 3]>,
+    /// Vertical field of view in degrees.
+    #[serde(default)]
+    pub fov: Option<f64>,
+    /// Width divided by height of the viewport, 1 if unset.
+    #[serde(default)]
+    pub aspect: Option<f64>,
+    #[serde(default)]
+    pub near: Option<f64>,
+    #[serde(default)]
+    pub far: Option<f64>,
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// `a` scaled to length 1, `None` for vectors too short to have a direction.
+fn normalize(a: [f64; 3]) -> Option<[f64; 3]> {
+    let length = dot(a, a).sqrt();
+    (length.is_finite() && length > 1e-9).then(|| [a[0] / length, a[1] / length, a[2] / length])
+}
+
+fn add_scaled(a: [f64; 3], b: [f64; 3], scale: f64) -> [f64; 3] {
+    [
+        a[0] + b[0] * scale,
+        a[1] + b[1] * scale,
+        a[2] + b[2] * scale,
+    ]
 }
 
 impl CameraInfo {
@@ -25,49 +73,736 @@ impl CameraInfo {
         );
         Viewbox { box_min, box_max }
     }
+
+    /// Orthonormal frame and extent of the view frustum, see `to_frustum`.
+    fn perspective(&self) -> Option<Perspective> {
+        let forward = normalize(self.direction?)?;
+        let right = normalize(cross(forward, normalize(self.up?)?))?;
+        let up = cross(right, forward);
+        let half_height = (self.fov?.to_radians() / 2.0).tan();
+        Some(Perspective {
+            forward,
+            right,
+            up,
+            half_width: half_height * self.aspect.unwrap_or(1.0),
+            half_height,
+            near: self.near?,
+            far: self.far?,
+        })
+    }
+
+    /// Lower and upper corner of an axis aligned box around the view, around the frustum if
+    /// there is one and the viewbox otherwise.
+    pub fn bounds(&self) -> ([f64; 3], [f64; 3]) {
+        let position = [self.x, self.y, self.z];
+        let perspective = match self.perspective() {
+            Some(perspective) => perspective,
+            None => {
+                let half_size = self.size / 2.0;
+                return (
+                    position.map(|p| p - half_size),
+                    position.map(|p| p + half_size),
+                );
+            }
+        };
+
+        let mut lower = [f64::INFINITY; 3];
+        let mut upper = [f64::NEG_INFINITY; 3];
+        for distance in [perspective.near, perspective.far] {
+            let centre = add_scaled(position, perspective.forward, distance);
+            for horizontal in [-1.0, 1.0] {
+                for vertical in [-1.0, 1.0] {
+                    let corner = add_scaled(
+                        add_scaled(
+                            centre,
+                            perspective.right,
+                            horizontal * perspective.half_width * distance,
+                        ),
+                        perspective.up,
+                        vertical * perspective.half_height * distance,
+                    );
+                    for k in 0..3 {
+                        lower[k] = lower[k].min(corner[k]);
+                        upper[k] = upper[k].max(corner[k]);
+                    }
+                }
+            }
+        }
+        (lower, upper)
+    }
+
+    /// The view frustum, `None` unless direction, up, fov, near and far are all given. Also
+    /// `None` if direction or up is zero or both are parallel, such cameras have no frustum.
+    pub fn to_frustum(&self) -> Option<Frustum> {
+        let Perspective {
+            forward,
+            right,
+            up,
+            half_width,
+            half_height,
+            near,
+            far,
+        } = self.perspective()?;
+        let position = [self.x, self.y, self.z];
+
+        // Inward pointing normals of the side planes, all of them contain the position.
+        let sides = [
+            cross(add_scaled(forward, right, -half_width), up),
+            cross(up, add_scaled(forward, right, half_width)),
+            cross(right, add_scaled(forward, up, -half_height)),
+            cross(add_scaled(forward, up, half_height), right),
+        ];
+        let mut planes = sides
+            .iter()
+            .map(|normal| plane(*normal, position))
+            .collect::<Option<Vec<Plane>>>()?;
+        planes.push(plane(forward, add_scaled(position, forward, near))?);
+        planes.push(plane(
+            [-forward[0], -forward[1], -forward[2]],
+            add_scaled(position, forward, far),
+        )?);
+        Some(Frustum { planes })
+    }
+}
+
+struct Perspective {
+    forward: [f64; 3],
+    right: [f64; 3],
+    up: [f64; 3],
+    /// Half the width and height of the view at distance 1.
+    half_width: f64,
+    half_height: f64,
+    near: f64,
+    far: f64,
+}
+
+/// Plane with `normal` through `point`.
+fn plane(normal: [f64; 3], point: [f64; 3]) -> Option<Plane> {
+    let normal = normalize(normal)?;
+    Some(Plane {
+        normal: RustVec3::new(normal[0], normal[1], normal[2]),
+        offset: -dot(normal, point),
+    })
+}
+
+/// Element type of the particle arrays sent to clients.
+pub trait ParticleFloat: Copy + Default + Serialize + Send + 'static {
+    /// Size in bytes, written to the header of the binary encoding.
+    const SIZE: usize;
+
+    fn from_f64(value: f64) -> Self;
+
+    fn extend_le_bytes(self, buffer: &mut Vec<u8>);
+}
+
+impl ParticleFloat for f64 {
+    const SIZE: usize = 8;
+
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn extend_le_bytes(self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl ParticleFloat for f32 {
+    const SIZE: usize = 4;
+
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn extend_le_bytes(self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Precision {
+    F32,
+    #[default]
+    F64,
+}
+
+/// How many batches of `batch_size_lod` particles an intersecting node gets per request.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LodPolicy {
+    /// One batch for every node.
+    #[default]
+    Uniform,
+    /// `scale * node size / distance to the camera` batches, at least `min_batches` and at
+    /// most `max_batches`. Nodes that have not been sent anything yet get at least one batch.
+    ScreenSize {
+        #[serde(default = "default_screen_size_scale")]
+        scale: f64,
+        #[serde(default)]
+        min_batches: i64,
+        #[serde(default = "default_screen_size_max_batches")]
+        max_batches: i64,
+    },
+}
+
+fn default_screen_size_scale() -> f64 {
+    1.0
+}
+
+fn default_screen_size_max_batches() -> i64 {
+    16
+}
+
+impl LodPolicy {
+    pub fn n_batches(
+        &self,
+        node: &NodeInfo,
+        camera_position: [f64; 3],
+        level_of_detail: i64,
+    ) -> i64 {
+        match self {
+            LodPolicy::Uniform => 1,
+            LodPolicy::ScreenSize {
+                scale,
+                min_batches,
+                max_batches,
+            } => {
+                let distance = node.distance_to(camera_position);
+                let n_batches = if distance > 0.0 {
+                    (scale * node.size / distance).round() as i64
+                } else {
+                    *max_batches
+                };
+                let n_batches = n_batches.max(*min_batches).min(*max_batches);
+                if level_of_detail == 0 {
+                    n_batches.max(1)
+                } else {
+                    n_batches
+                }
+            }
+        }
+    }
+}
+
+/// Particle arrays of a `LodResult`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum LodField {
+    /// `splines_a` to `splines_d`.
+    Splines,
+    /// `relevant_densities_flat` together with `min_d` and `max_d`.
+    Densities,
+    Coordinates,
+    VoronoiDiameter,
+}
+
+impl LodField {
+    pub const ALL: [LodField; 4] = [
+        LodField::Splines,
+        LodField::Densities,
+        LodField::Coordinates,
+        LodField::VoronoiDiameter,
+    ];
+}
+
+/// Entry of `WebServiceConfig::loaded_fields`, a particle array or the name of a further
+/// particle field in the snapdir, see `InitResponse::fields`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum LoadedField {
+    Lod(LodField),
+    Particle(String),
+}
+
+/// Densities of the particles a client wants to see, absolute or as quantiles of the density
+/// distribution of the snapshot, see `InitResponse::quantiles`. Missing bounds are open, if
+/// both kinds are given the tighter one applies.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct DensityRange {
+    #[serde(default)]
+    pub min_density: Option<f64>,
+    #[serde(default)]
+    pub max_density: Option<f64>,
+    /// Between 0 and 1.
+    #[serde(default)]
+    pub min_quantile: Option<f64>,
+    #[serde(default)]
+    pub max_quantile: Option<f64>,
+}
+
+impl DensityRange {
+    /// Lower and upper density bound, `None` if no bound is given. `quantiles` are the evenly
+    /// spaced quantiles from 0 to 1, values in between are interpolated linearly.
+    pub fn bounds(&self, quantiles: ArrayView1<f64>) -> Option<(f64, f64)> {
+        if self.min_density.is_none()
+            && self.max_density.is_none()
+            && self.min_quantile.is_none()
+            && self.max_quantile.is_none()
+        {
+            return None;
+        }
+        let quantile = |q: f64| -> Option<f64> {
+            let last = quantiles.len().checked_sub(1)?;
+            let position = q.clamp(0.0, 1.0) * last as f64;
+            let lower = position.floor() as usize;
+            let upper = min(lower + 1, last);
+            let fraction = position - lower as f64;
+            Some(quantiles[lower] + (quantiles[upper] - quantiles[lower]) * fraction)
+        };
+
+        let min_density = [self.min_density, self.min_quantile.and_then(quantile)]
+            .into_iter()
+            .flatten()
+            .fold(f64::NEG_INFINITY, f64::max);
+        let max_density = [self.max_density, self.max_quantile.and_then(quantile)]
+            .into_iter()
+            .flatten()
+            .fold(f64::INFINITY, f64::min);
+        Some((min_density, max_density))
+    }
 }
 
 #[derive(Serialize)]
-pub struct LodResult {
-    pub splines_a: Vec<f64>,
-    pub splines_b: Vec<f64>,
-    pub splines_c: Vec<f64>,
-    pub splines_d: Vec<f64>,
+pub struct LodResult<F = f64> {
+    pub splines_a: Vec<F>,
+    pub splines_b: Vec<F>,
+    pub splines_c: Vec<F>,
+    pub splines_d: Vec<F>,
     #[serde(rename = "densities")]
-    pub relevant_densities_flat: Vec<f64>,
+    pub relevant_densities_flat: Vec<F>,
     #[serde(rename = "coordinates")]
-    pub relevant_coordinates: Vec<Vec<f64>>,
+    pub relevant_coordinates: Vec<Vec<F>>,
     #[serde(rename = "voronoi_diameter_extended")]
-    pub relevant_voronoi_diameter_extended: Vec<f64>,
-    #[serde(rename = "level_of_detail")]
-    pub client_level_of_detail: HashMap<i64, i64>,
+    pub relevant_voronoi_diameter_extended: Vec<F>,
+    /// Only sent to clients without a session, which have to send it back with the next request.
+    #[serde(rename = "level_of_detail", skip_serializing_if = "Option::is_none")]
+    pub client_level_of_detail: Option<HashMap<i64, i64>>,
     #[serde(rename = "min_density")]
     pub min_d: f64,
     #[serde(rename = "max_density")]
     pub max_d: f64,
     #[serde(rename = "nParticles")]
     pub n_particles: usize,
+    /// Arrays that were extracted, the others are empty.
+    pub fields: Vec<LodField>,
     #[serde(rename = "snapnum")]
     pub snapshot_id: usize,
-    pub node_indices: Vec<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_indices: Option<Vec<i64>>,
+    /// Set if coordinates and `splines_a` are relative to this point, the camera centre.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<[f64; 3]>,
+    /// Values of the colouring field requested by the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<ColorValues<F>>,
+    /// Positions and densities at the time requested by the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interpolated: Option<InterpolatedValues<F>>,
+    /// Whether another request with the same view would not send anything, used by the lod
+    /// stream to tell when it is done.
+    #[serde(skip)]
+    pub refined: bool,
+}
+
+/// Per-particle values of a field from `InitResponse::fields`, the magnitude for vector fields.
+#[derive(Serialize)]
+pub struct ColorValues<F = f64> {
+    pub field: String,
+    pub values: Vec<F>,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Particles between this snapshot and the next, for clients that do not evaluate the splines.
+#[derive(Serialize)]
+pub struct InterpolatedValues<F = f64> {
+    /// Fraction of the way to the next snapshot, from 0 to 1.
+    pub time: f64,
+    /// `3n` positions, relative to `LodResult::origin` like `splines_a`.
+    pub positions: Vec<F>,
+    /// Densities interpolated linearly between both snapshots.
+    pub densities: Vec<F>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryBudget {
+    Bytes(usize),
+    Percent(f64),
+}
+
+impl MemoryBudget {
+    pub fn as_bytes(&self) -> anyhow::Result<usize> {
+        match self {
+            MemoryBudget::Bytes(bytes) => Ok(*bytes),
+            MemoryBudget::Percent(percent) => {
+                if !(0.0..=100.0).contains(percent) {
+                    return Err(anyhow!(
+                        "Memory budget percentage must be within [0, 100], got {}",
+                        percent
+                    ));
+                }
+                let total = total_memory_bytes()?;
+                Ok((total as f64 * percent / 100.0) as usize)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Evict the entry that was requested least recently.
+    #[default]
+    Lru,
+    /// Evict the entry that was requested least often, ties are broken by recency.
+    Lfu,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NpyLoadMode {
+    /// Copy the arrays into heap memory.
+    #[default]
+    Read,
+    /// Map the files into memory, pages are loaded lazily by the OS.
+    Mmap,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PrefetchConfig {
+    /// Number of snapshots before and after a requested one that are loaded in background.
+    #[serde(default)]
+    pub depth: usize,
+    /// Overrides `depth` for individual simulations.
+    #[serde(default)]
+    pub simulations: HashMap<String, usize>,
+}
+
+impl PrefetchConfig {
+    pub fn depth_for(&self, simulation: &str) -> usize {
+        *self.simulations.get(simulation).unwrap_or(&self.depth)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChangePolicy {
+    /// Keep serving cached entries when their files change on disk, nothing is watched.
+    #[default]
+    Ignore,
+    /// Drop entries whose files changed, they are loaded again on the next request.
+    Invalidate,
+    /// Reload entries whose files changed, the old entry is served until the reload finished.
+    Reload,
+}
+
+/// Storage backend of a simulation.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    /// Preprocessed npy files in `basedir/simulation/snapdir_NNN/`. Uses the global `basedir`
+    /// and `npy_load_mode` unless overridden.
+    NpyDir {
+        #[serde(default)]
+        basedir: Option<String>,
+        #[serde(default)]
+        npy_load_mode: Option<NpyLoadMode>,
+    },
+    /// Particle data read from the `snap_NNN.*.hdf5` chunks in the snapdir, the products of the
+    /// preprocessing still as npy files next to them.
+    Hdf5 {
+        #[serde(default)]
+        basedir: Option<String>,
+        #[serde(default)]
+        npy_load_mode: Option<NpyLoadMode>,
+    },
+    /// The npy layout in the object store configured under `s3`, spilled to local disk before
+    /// loading. Uses the global `prefix` and `npy_load_mode` unless overridden.
+    S3 {
+        #[serde(default)]
+        prefix: Option<String>,
+        #[serde(default)]
+        npy_load_mode: Option<NpyLoadMode>,
+    },
+}
+
+/// Connection to an S3 compatible object store, addressed path-style.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct S3Config {
+    /// e.g. `http://localhost:9000` for a local MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Key prefix under which the simulations are stored.
+    #[serde(default)]
+    pub prefix: String,
+    /// Local directory the downloaded files are kept in, in the npy layout.
+    pub spill_dir: String,
+    /// Size of the ranged reads in bytes.
+    #[serde(default = "default_s3_range_size")]
+    pub range_size: u64,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
 }
 
-#[derive(Serialize, Deserialize)]
+fn default_s3_range_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// Snapshots of a simulation that are loaded on startup, either listed or as an inclusive range.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PreloadEntry {
+    pub simulation: String,
+    #[serde(default)]
+    pub snapshot_ids: Vec<usize>,
+    #[serde(default)]
+    pub range: Option<(usize, usize)>,
+}
+
+impl PreloadEntry {
+    pub fn snapshot_ids(&self) -> Vec<usize> {
+        let mut snapshot_ids = self.snapshot_ids.clone();
+        if let Some((first, last)) = self.range {
+            snapshot_ids.extend(first..=last);
+        }
+        snapshot_ids
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct WebServiceConfig {
     pub basedir: String,
     pub metadata_url: String,
     pub port: usize,
     pub cache_server_url: String,
+    /// Upper bound for the memory held by cached snapshots. No limit if unset.
+    #[serde(default)]
+    pub memory_budget: Option<MemoryBudget>,
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+    #[serde(default)]
+    pub npy_load_mode: NpyLoadMode,
+    #[serde(default)]
+    pub prefetch: PrefetchConfig,
+    /// Bearer token for the `/v1/cache` administration routes. They are disabled if unset.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Snapshots that are loaded in background on startup.
+    #[serde(default)]
+    pub preload: Vec<PreloadEntry>,
+    #[serde(default)]
+    pub on_file_change: FileChangePolicy,
+    /// Seconds to wait after a change on disk before the cached entries are checked.
+    #[serde(default = "default_file_change_delay_secs")]
+    pub file_change_delay_secs: u64,
+    /// Storage backend per simulation, simulations without an entry use the npy layout in
+    /// `basedir`.
+    #[serde(default)]
+    pub sources: HashMap<String, SourceConfig>,
+    /// Object store used by sources of type `s3`.
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    #[serde(default)]
+    pub sessions: SessionConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Particle arrays and further particle fields that are loaded per simulation, everything
+    /// for simulations that are not listed. Clients can only request what is loaded. The
+    /// entry `ParticleIDs` loads the ParticleIDs, transitions between snapshots need them.
+    #[serde(default)]
+    pub loaded_fields: HashMap<String, Vec<LoadedField>>,
+}
+
+fn default_file_change_delay_secs() -> u64 {
+    5
+}
+
+/// Compression of the lod and init responses, see `compression::compress_response`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Responses smaller than this many bytes are sent uncompressed.
+    pub min_size: usize,
+    /// 0 to 9.
+    pub gzip_level: u32,
+    /// 0 to 11.
+    pub brotli_level: u32,
+    /// 1 to 22.
+    pub zstd_level: i32,
+    /// Byte-shuffle the float arrays of binary responses that are compressed.
+    pub shuffle: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 4096,
+            gzip_level: 6,
+            brotli_level: 4,
+            zstd_level: 3,
+            shuffle: true,
+        }
+    }
+}
+
+/// Server side client sessions, see `session::SessionStore`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionConfig {
+    /// Seconds without requests after which a session is dropped.
+    pub ttl_secs: u64,
+    /// Upper bound in bytes for the memory held by all sessions together.
+    pub memory_budget: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl_secs: 30 * 60,
+            memory_budget: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Camera update sent by clients of the lod stream, with the same parameters as a request
+/// to `/v1/get/splines/{simulation}/{snapshot_id}`.
+#[derive(Deserialize, Clone)]
+pub struct StreamUpdate {
+    #[serde(flatten)]
+    pub parameters: LodParameters,
+}
+
+/// Messages sent by clients of the lod stream.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum StreamMessage {
+    /// The client processed all batches up to and including the one with this sequence number.
+    Ack {
+        ack: u64,
+    },
+    Update(StreamUpdate),
+}
+
+/// Batch of the lod stream, sent as `{"type": "batch", ...}` besides the `StreamEvent`s.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "batch")]
+pub struct StreamBatch<F = f64> {
+    /// Increasing per connection, clients acknowledge batches by it. Batches computed for an
+    /// outdated camera are dropped, so numbers may be skipped.
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub result: LodResult<F>,
+}
+
+/// Messages sent by the server on the lod stream.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    /// Everything inside the current view was sent, nothing follows until the camera moves.
+    Done {
+        #[serde(rename = "snapnum")]
+        snapshot_id: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// The snapshot a client switches from, see `ClientState::transition`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Transition {
+    pub snapshot_id: usize,
+    /// Level of detail the client has of that snapshot, ignored with a session.
+    #[serde(default)]
+    pub level_of_detail: HashMap<i64, i64>,
 }
 
 #[derive(Deserialize)]
 pub struct ClientState {
-    pub node_indices: Vec<i64>,
+    /// Issued by `/v1/get/init`. With a session the server keeps track of the level of detail
+    /// and `level_of_detail` is ignored.
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
     pub level_of_detail: HashMap<i64, i64>,
+    /// Instead of refining this snapshot, send the particles the client has of the snapshot it
+    /// switches from as they are in this one, matched by ParticleIDs. With a session later
+    /// requests do not send them again. Without one they are sent again as the nodes refine,
+    /// clients drop the transitioned particles before they add the first refined batch. If the
+    /// snapshot it switches from is no longer cached, this snapshot is refined as without it.
+    #[serde(default)]
+    pub transition: Option<Transition>,
+    #[serde(flatten)]
+    pub parameters: LodParameters,
+}
+
+/// What a client asks for in a lod request, the same for
+/// `/v1/get/splines/{simulation}/{snapshot_id}` and the lod stream.
+#[derive(Deserialize, Clone)]
+pub struct LodParameters {
+    /// Precision of the particle arrays in the response.
+    #[serde(default)]
+    pub precision: Precision,
+    /// Send positions relative to the camera centre, recommended with `f32` in large boxes.
+    #[serde(default)]
+    pub relative_to_camera: bool,
+    /// Batches per node, one for every node unless given.
+    #[serde(default)]
+    pub lod_policy: LodPolicy,
+    /// Upper bound for the number of particles in the response, unbounded unless given.
+    #[serde(default)]
+    pub max_particles: Option<usize>,
+    #[serde(flatten)]
+    pub density_range: DensityRange,
+    /// Arrays in the response, all that are loaded for the simulation unless given.
+    #[serde(default)]
+    pub fields: Option<Vec<LodField>>,
+    /// Name of a particle field from `InitResponse::fields` to colour the particles by.
+    #[serde(default)]
+    pub color_field: Option<String>,
+    /// Also evaluate positions and densities at this fraction of the way to the next snapshot,
+    /// from 0 to 1, see `LodResult::interpolated`.
+    #[serde(default)]
+    pub time: Option<f64>,
+    /// Particles per batch, at least 1.
     pub batch_size_lod: i64,
     pub camera_information: CameraInfo,
 }
 
+impl LodParameters {
+    /// Reject parameters that are invalid for every snapshot.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.batch_size_lod <= 0 {
+            return Err(anyhow!(
+                "batch_size_lod has to be positive, got {}.",
+                self.batch_size_lod
+            ));
+        }
+        if let Some(time) = self.time {
+            if !(0.0..=1.0).contains(&time) {
+                return Err(anyhow!("Time {} is not between 0 and 1.", time));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Query of `/v1/get/init`.
+#[derive(Deserialize)]
+pub struct InitQuery {
+    /// Session from an earlier init, kept if it is still valid instead of creating a new one.
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct InitResponse {
     #[serde(rename = "available_snaps")]
@@ -77,4 +812,230 @@ pub struct InitResponse {
     #[serde(rename = "density_quantiles")]
     pub quantiles: Vec<f64>,
     pub n_quantiles: usize,
+    pub session_id: String,
+    /// Particle fields besides density that clients can colour by. These are the npy files
+    /// in the snapdir, for HDF5 snapshots the other `PartType0` datasets are not included.
+    pub fields: Vec<ParticleFieldInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ParticleFieldInfo {
+    pub name: String,
+    /// 1 for scalar fields.
+    pub components: usize,
+    /// Evenly spaced quantiles from 0 to 1, of the magnitude for vector fields.
+    pub quantiles: Vec<f64>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CurrentCacheResponse {
+    /// Snapshots per simulation that were requested by clients.
+    pub cached: HashMap<String, Vec<usize>>,
+    /// Snapshots per simulation that were loaded by the prefetcher and not requested yet.
+    pub prefetched: HashMap<String, Vec<usize>>,
+}
+
+#[derive(Serialize)]
+pub struct CacheLoadResponse {
+    pub simulation: String,
+    pub snapshot_id: usize,
+    pub size_in_bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct CacheEvictResponse {
+    pub n_evicted: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inside(frustum: &Frustum, point: [f64; 3]) -> bool {
+        frustum.planes.iter().all(|plane| {
+            plane.normal.x * point[0]
+                + plane.normal.y * point[1]
+                + plane.normal.z * point[2]
+                + plane.offset
+                >= 0.0
+        })
+    }
+
+    #[test]
+    fn test_frustum() {
+        let camera = CameraInfo {
+            x: 10.0,
+            y: 0.0,
+            z: 0.0,
+            direction: Some([1.0, 0.0, 0.0]),
+            up: Some([0.0, 0.0, 1.0]),
+            fov: Some(90.0),
+            aspect: Some(2.0),
+            near: Some(1.0),
+            far: Some(100.0),
+            ..Default::default()
+        };
+        let frustum = camera.to_frustum().unwrap();
+        assert_eq!(frustum.planes.len(), 6);
+
+        assert!(inside(&frustum, [20.0, 0.0, 0.0]));
+        // Behind the camera, in front of the near and behind the far plane.
+        assert!(!inside(&frustum, [0.0, 0.0, 0.0]));
+        assert!(!inside(&frustum, [10.5, 0.0, 0.0]));
+        assert!(!inside(&frustum, [120.0, 0.0, 0.0]));
+        // 90 degrees vertically, twice as wide horizontally.
+        assert!(inside(&frustum, [20.0, 0.0, 9.0]));
+        assert!(!inside(&frustum, [20.0, 0.0, 11.0]));
+        assert!(inside(&frustum, [20.0, 19.0, 0.0]));
+        assert!(!inside(&frustum, [20.0, -21.0, 0.0]));
+    }
+
+    #[test]
+    fn test_bounds() {
+        let camera = CameraInfo {
+            x: 10.0,
+            y: 0.0,
+            z: 0.0,
+            size: 4.0,
+            direction: Some([1.0, 0.0, 0.0]),
+            up: Some([0.0, 0.0, 1.0]),
+            fov: Some(90.0),
+            aspect: Some(2.0),
+            near: Some(1.0),
+            far: Some(100.0),
+        };
+        let (lower, upper) = camera.bounds();
+        let expected = ([11.0, -200.0, -100.0], [110.0, 200.0, 100.0]);
+        for k in 0..3 {
+            assert!((lower[k] - expected.0[k]).abs() < 1e-9);
+            assert!((upper[k] - expected.1[k]).abs() < 1e-9);
+        }
+
+        let viewbox = CameraInfo {
+            direction: None,
+            ..camera
+        };
+        assert_eq!(viewbox.bounds(), ([8.0, -2.0, -2.0], [12.0, 2.0, 2.0]));
+    }
+
+    #[test]
+    fn test_no_frustum_without_perspective() {
+        let camera = CameraInfo {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+            size: 4.0,
+            ..Default::default()
+        };
+        assert!(camera.to_frustum().is_none());
+    }
+
+    #[test]
+    fn test_check_lod_parameters() {
+        let parameters = |json: &str| serde_json::from_str::<LodParameters>(json).unwrap();
+        let camera = r#""camera_information": {"x": 0.0, "y": 0.0, "z": 0.0, "size": 1.0}"#;
+
+        assert!(
+            parameters(&format!(r#"{{"batch_size_lod": 1, {}}}"#, camera))
+                .check()
+                .is_ok()
+        );
+        assert!(
+            parameters(&format!(r#"{{"batch_size_lod": 0, {}}}"#, camera))
+                .check()
+                .is_err()
+        );
+        assert!(
+            parameters(&format!(r#"{{"batch_size_lod": -5, {}}}"#, camera))
+                .check()
+                .is_err()
+        );
+        assert!(parameters(&format!(
+            r#"{{"batch_size_lod": 1, "time": 1.5, {}}}"#,
+            camera
+        ))
+        .check()
+        .is_err());
+    }
+
+    #[test]
+    fn test_no_frustum_for_degenerate_camera() {
+        let camera = CameraInfo {
+            x: 10.0,
+            y: 0.0,
+            z: 0.0,
+            size: 4.0,
+            direction: Some([0.0, 0.0, 2.0]),
+            up: Some([0.0, 0.0, 1.0]),
+            fov: Some(90.0),
+            aspect: Some(1.0),
+            near: Some(1.0),
+            far: Some(100.0),
+        };
+        assert!(camera.to_frustum().is_none());
+        let zero_direction = CameraInfo {
+            direction: Some([0.0; 3]),
+            ..camera.clone()
+        };
+        assert!(zero_direction.to_frustum().is_none());
+        let zero_up = CameraInfo {
+            direction: Some([1.0, 0.0, 0.0]),
+            up: Some([0.0; 3]),
+            ..camera.clone()
+        };
+        assert!(zero_up.to_frustum().is_none());
+        let valid = CameraInfo {
+            direction: Some([1.0, 0.0, 0.0]),
+            ..camera
+        };
+        assert!(valid.to_frustum().is_some());
+    }
+
+    #[test]
+    fn test_screen_size_policy() {
+        let policy = LodPolicy::ScreenSize {
+            scale: 4.0,
+            min_batches: 0,
+            max_batches: 8,
+        };
+        let node = NodeInfo {
+            index: 0,
+            origin: RustVec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            size: 1.0,
+        };
+        assert_eq!(LodPolicy::Uniform.n_batches(&node, [100.0, 0.0, 0.0], 3), 1);
+        // Inside of the node and right next to it
+        assert_eq!(policy.n_batches(&node, [0.5, 0.5, 0.5], 3), 8);
+        assert_eq!(policy.n_batches(&node, [1.0, 1.5, 0.5], 3), 8);
+        assert_eq!(policy.n_batches(&node, [3.0, 0.5, 0.5], 3), 2);
+        // Far away nodes stay coarse, but get a first batch
+        assert_eq!(policy.n_batches(&node, [100.0, 0.0, 0.0], 3), 0);
+        assert_eq!(policy.n_batches(&node, [100.0, 0.0, 0.0], 0), 1);
+    }
+
+    #[test]
+    fn test_density_range_bounds() {
+        let quantiles = ndarray::array![0.0, 10.0, 20.0, 40.0, 80.0];
+        let quantiles = quantiles.view();
+        assert_eq!(DensityRange::default().bounds(quantiles), None);
+
+        let range = DensityRange {
+            min_quantile: Some(0.625),
+            ..Default::default()
+        };
+        assert_eq!(range.bounds(quantiles), Some((30.0, f64::INFINITY)));
+
+        // The tighter of both bounds applies.
+        let range = DensityRange {
+            min_density: Some(35.0),
+            min_quantile: Some(0.625),
+            max_density: Some(70.0),
+            max_quantile: Some(0.875),
+        };
+        assert_eq!(range.bounds(quantiles), Some((35.0, 60.0)));
+    }
 }
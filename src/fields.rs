@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use ndarray::{ArrayViewD, Axis, Ix1, IxDyn};
+
+use super::dto::NpyLoadMode;
+use super::npy::NpyArray;
+
+use anyhow::{anyhow, Context};
+
+/// Values of a particle field in the precision of its file.
+pub enum FieldValues {
+    F64(NpyArray<f64, IxDyn>),
+    F32(NpyArray<f32, IxDyn>),
+}
+
+impl FieldValues {
+    pub fn shape(&self) -> Vec<usize> {
+        match self {
+            FieldValues::F64(values) => values.view().shape().to_vec(),
+            FieldValues::F32(values) => values.view().shape().to_vec(),
+        }
+    }
+}
+
+/// A per-particle quantity of a snapshot besides density and coordinates, read from
+/// `<name>.npy` in the snapdir. Scalar fields have the shape `(n,)`, vector fields
+/// `(n, components)`. Files of f32 values are widened to f64 on access.
+pub struct ParticleField {
+    pub values: FieldValues,
+    n_quantiles: usize,
+    /// Evenly spaced quantiles from 0 to 1, of the magnitude for vector fields. Read from
+    /// `<name>_quantiles.npy` if the preprocessing wrote it, computed on first use otherwise.
+    quantiles: OnceLock<Vec<f64>>,
+}
+
+impl ParticleField {
+    pub fn open(
+        path: &Path,
+        n_particles: usize,
+        n_quantiles: usize,
+        npy_load_mode: NpyLoadMode,
+    ) -> anyhow::Result<Self> {
+        let values = match NpyArray::<f64, IxDyn>::open(path, npy_load_mode) {
+            Ok(values) => FieldValues::F64(values),
+            Err(err) => match NpyArray::<f32, IxDyn>::open(path, npy_load_mode) {
+                Ok(values) => FieldValues::F32(values),
+                // Neither f64 nor f32, report why it is no f64 array.
+                Err(_) => return Err(err),
+            },
+        };
+        let shape = values.shape();
+        if shape.len() > 2 || shape.first() != Some(&n_particles) {
+            return Err(anyhow!(
+                "Expected {} particles but {} has the shape {:?}",
+                n_particles,
+                path.display(),
+                shape
+            ));
+        }
+
+        let quantiles = OnceLock::new();
+        let quantiles_path = quantiles_path(path);
+        if quantiles_path.exists() {
+            let precomputed: NpyArray<f64, Ix1> =
+                NpyArray::open(&quantiles_path, NpyLoadMode::Read)?;
+            let _ = quantiles.set(precomputed.view().to_vec());
+        }
+        Ok(ParticleField {
+            values,
+            n_quantiles,
+            quantiles,
+        })
+    }
+
+    pub fn components(&self) -> usize {
+        self.values.shape().get(1).copied().unwrap_or(1)
+    }
+
+    /// Value of particle `id` used for colouring, see `scalar`.
+    pub fn scalar(&self, id: usize) -> f64 {
+        match &self.values {
+            FieldValues::F64(values) => scalar(&values.view(), id),
+            FieldValues::F32(values) => scalar(&values.view(), id),
+        }
+    }
+
+    /// Evenly spaced quantiles from 0 to 1 of the values used for colouring. Sorting all
+    /// values is slow and reads every page of mapped files, so it only happens once and only
+    /// without precomputed quantiles.
+    pub fn quantiles(&self) -> &[f64] {
+        self.quantiles.get_or_init(|| {
+            let n_particles = self.values.shape()[0];
+            let magnitudes = (0..n_particles).map(|id| self.scalar(id)).collect();
+            quantiles(magnitudes, self.n_quantiles)
+        })
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        match &self.values {
+            FieldValues::F64(values) => values.size_in_bytes(),
+            FieldValues::F32(values) => values.size_in_bytes(),
+        }
+    }
+}
+
+/// Value of particle `id` used for colouring, the magnitude for vector fields.
+pub fn scalar<A: Copy + Into<f64>>(values: &ArrayViewD<A>, id: usize) -> f64 {
+    if values.ndim() == 1 {
+        values[[id]].into()
+    } else {
+        values
+            .index_axis(Axis(0), id)
+            .iter()
+            .map(|value| {
+                let value: f64 = (*value).into();
+                value * value
+            })
+            .sum::<f64>()
+            .sqrt()
+    }
+}
+
+/// `n_quantiles` evenly spaced quantiles from 0 to 1 of `values`.
+pub fn quantiles(mut values: Vec<f64>, n_quantiles: usize) -> Vec<f64> {
+    if values.is_empty() || n_quantiles == 0 {
+        return vec![];
+    }
+    values.sort_unstable_by(|a, b| a.total_cmp(b));
+    let last = (values.len() - 1) as f64;
+    (0..n_quantiles)
+        .map(|i| {
+            let q = if n_quantiles == 1 {
+                0.0
+            } else {
+                i as f64 / (n_quantiles - 1) as f64
+            };
+            values[(q * last).round() as usize]
+        })
+        .collect()
+}
+
+/// Name of the field stored in `file_name`, `None` unless it is an npy file that is not one of
+/// `known` and does not hold the quantiles of a field.
+pub fn field_name(file_name: &str, known: &[&str]) -> Option<String> {
+    if known.contains(&file_name) {
+        return None;
+    }
+    file_name
+        .strip_suffix(".npy")
+        .filter(|name| !name.is_empty() && !name.ends_with("_quantiles"))
+        .map(|name| name.to_string())
+}
+
+/// Files of all fields in `snapdir` that are not one of the `known` files, by field name.
+pub fn discover(snapdir: &str, known: &[&str]) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(snapdir).with_context(|| format!("Failed to list {}", snapdir))? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .context("Failed to get filename.")?
+            .to_string_lossy()
+            .to_string();
+        if let Some(name) = field_name(&file_name, known) {
+            files.push((name, path));
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Open the fields in `files`, see `discover`. Files that are no f64 or f32 arrays with a row
+/// per particle are skipped.
+pub fn open_all(
+    files: &[(String, PathBuf)],
+    n_particles: usize,
+    n_quantiles: usize,
+    npy_load_mode: NpyLoadMode,
+) -> HashMap<String, ParticleField> {
+    let mut fields = HashMap::new();
+    for (name, path) in files {
+        match ParticleField::open(path, n_particles, n_quantiles, npy_load_mode) {
+            Ok(field) => {
+                fields.insert(name.clone(), field);
+            }
+            Err(err) => log::warn!("skipping particle field {}: {:?}", name, err),
+        }
+    }
+    fields
+}
+
+/// Path of the precomputed quantiles of the field in `path`, `<name>_quantiles.npy`.
+pub fn quantiles_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(
+        "{}_quantiles.npy",
+        path.file_stem().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_quantiles() {
+        let values = vec![4.0, 0.0, 2.0, 1.0, 3.0];
+        assert_eq!(quantiles(values.clone(), 3), vec![0.0, 2.0, 4.0]);
+        assert_eq!(quantiles(values, 5), vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+        assert!(quantiles(vec![], 5).is_empty());
+    }
+
+    #[test]
+    fn test_scalar() {
+        let vectors = array![[3.0, 4.0, 0.0], [1.0, 0.0, 0.0]].into_dyn();
+        assert_eq!(scalar(&vectors.view(), 0), 5.0);
+        let values = array![7.0, 8.0].into_dyn();
+        assert_eq!(scalar(&values.view(), 1), 8.0);
+    }
+
+    #[test]
+    fn test_f32_field() {
+        let path = std::env::temp_dir().join("cache_server_f32_field_test.npy");
+        let values = array![[3.0f32, 4.0], [1.0, 0.0], [0.0, 2.0]];
+        ndarray_npy::write_npy(&path, &values).unwrap();
+
+        let field = ParticleField::open(&path, 3, 3, NpyLoadMode::Read).unwrap();
+        assert!(matches!(field.values, FieldValues::F32(_)));
+        assert_eq!(field.components(), 2);
+        assert_eq!(field.scalar(0), 5.0);
+        assert_eq!(field.quantiles(), &[1.0, 2.0, 5.0]);
+        assert!(ParticleField::open(&path, 4, 3, NpyLoadMode::Read).is_err());
+
+        // Precomputed quantiles are taken as they are.
+        let quantiles_path = path.with_file_name("cache_server_f32_field_test_quantiles.npy");
+        ndarray_npy::write_npy(&quantiles_path, &array![0.5, 1.5]).unwrap();
+        let field = ParticleField::open(&path, 3, 3, NpyLoadMode::Read).unwrap();
+        assert_eq!(field.quantiles(), &[0.5, 1.5]);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(quantiles_path).unwrap();
+    }
+
+    #[test]
+    fn test_field_name() {
+        let known = ["Density.npy", "splines.npy"];
+        assert_eq!(
+            field_name("Temperature.npy", &known),
+            Some("Temperature".to_string())
+        );
+        assert_eq!(field_name("Density.npy", &known), None);
+        assert_eq!(field_name("o3dOctree.json", &known), None);
+        assert_eq!(field_name("Velocities.npy.part", &known), None);
+        assert_eq!(field_name("Temperature_quantiles.npy", &known), None);
+    }
+}
@@ -0,0 +1,54 @@
+#![feature(result_option_inspect)]
+
+use expanduser::expanduser;
+use std::collections::HashMap;
+
+pub(crate) mod binary;
+pub(crate) mod bind;
+pub(crate) mod compression;
+pub(crate) mod data_cache;
+pub(crate) mod dto;
+pub(crate) mod fields;
+pub(crate) mod lod;
+pub(crate) mod npy;
+pub(crate) mod requesthandler;
+pub(crate) mod s3;
+mod server;
+pub(crate) mod session;
+pub(crate) mod source;
+pub(crate) mod stream;
+pub(crate) mod transition;
+pub(crate) mod utils;
+pub(crate) mod warmup;
+pub(crate) mod watch;
+
+#[doc(hidden)]
+pub use lod::bench_support;
+pub use server::run;
+
+impl ::std::default::Default for dto::WebServiceConfig {
+    fn default() -> Self {
+        Self {
+            basedir: expanduser("~/Documents/data/tng/manual_download/")
+                .expect("Failed to expand user.")
+                .display()
+                .to_string(),
+            metadata_url: "http://localhost:9999".to_string(),
+            port: 8000,
+            cache_server_url: "http://localhost:8000".to_string(),
+            memory_budget: None,
+            eviction_policy: dto::EvictionPolicy::Lru,
+            npy_load_mode: dto::NpyLoadMode::Read,
+            prefetch: dto::PrefetchConfig::default(),
+            admin_token: None,
+            preload: vec![],
+            on_file_change: dto::FileChangePolicy::Ignore,
+            file_change_delay_secs: 5,
+            sources: HashMap::new(),
+            s3: None,
+            sessions: dto::SessionConfig::default(),
+            compression: dto::CompressionConfig::default(),
+            loaded_fields: HashMap::new(),
+        }
+    }
+}
@@ -1,28 +1,235 @@
-use ndarray::{s, Array1, Array2, Array3};
+use ndarray::{s, ArrayView1, ArrayView2, ArrayView3};
+use rayon::prelude::*;
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use super::bind::ffi::{get_intersecting_node, Octree};
+use super::bind::ffi::{
+    get_intersecting_node_info, get_intersecting_node_info_frustum, NodeInfo, Octree, RustVec3,
+};
 use cxx::SharedPtr;
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+
+use super::data_cache::CacheEntry;
+use super::dto::{
+    CameraInfo, ColorValues, InterpolatedValues, LodField, LodParameters, LodPolicy, LodResult,
+    ParticleFloat,
+};
+use super::fields::ParticleField;
+
+/// Per request options of the lod calculation.
+#[derive(Default, Clone)]
+pub struct LodOptions {
+    /// Send coordinates and spline positions relative to the camera centre, see
+    /// `LodResult::origin`.
+    pub relative_to_camera: bool,
+    /// How many batches each node gets per request.
+    pub policy: LodPolicy,
+    /// Upper bound for the particles of one request, see `apply_particle_budget`.
+    pub max_particles: Option<usize>,
+    /// Side length of the periodic box, the view is wrapped across its boundaries if given.
+    pub box_size: Option<f64>,
+    /// Only particles with a density in `[min, max]` in this snapshot are sent. The level of
+    /// detail then counts batches of matching particles.
+    pub density_range: Option<(f64, f64)>,
+    /// Arrays to extract, all of them if unset.
+    pub fields: Option<Vec<LodField>>,
+    /// Time between this snapshot and the next to evaluate the particles at, see
+    /// `interpolate_particles`.
+    pub time: Option<f64>,
+}
+
+impl LodOptions {
+    /// The options a client asks for with `parameters`. Fails if the request can not be served
+    /// from what is loaded of `cache_entry`.
+    pub fn new(parameters: &LodParameters, cache_entry: &CacheEntry) -> anyhow::Result<Self> {
+        parameters.check()?;
+
+        // Requests can only be served from the arrays that are loaded.
+        let fields = parameters
+            .fields
+            .clone()
+            .unwrap_or_else(|| cache_entry.fields.clone());
+        if let Some(field) = fields
+            .iter()
+            .find(|field| !cache_entry.fields.contains(field))
+        {
+            return Err(anyhow!(
+                "Field {:?} is not loaded for this simulation.",
+                field
+            ));
+        }
+
+        let density_range = parameters
+            .density_range
+            .bounds(cache_entry.quantiles.view());
+        if density_range.is_some() && !cache_entry.fields.contains(&LodField::Densities) {
+            return Err(anyhow!(
+                "Filtering by density requires the densities to be loaded."
+            ));
+        }
+        if parameters.time.is_some()
+            && (!cache_entry.fields.contains(&LodField::Splines)
+                || !cache_entry.fields.contains(&LodField::Densities))
+        {
+            return Err(anyhow!(
+                "Interpolation requires the splines and densities to be loaded."
+            ));
+        }
+
+        Ok(LodOptions {
+            relative_to_camera: parameters.relative_to_camera,
+            policy: parameters.lod_policy.clone(),
+            max_particles: parameters.max_particles,
+            box_size: cache_entry.box_size,
+            density_range,
+            fields: Some(fields),
+            time: parameters.time,
+        })
+    }
+}
+
+/// Name and values of the particle field a client asks to colour by, if any.
+pub fn color_field<'a>(
+    parameters: &'a LodParameters,
+    cache_entry: &'a CacheEntry,
+) -> anyhow::Result<Option<(&'a str, &'a ParticleField)>> {
+    match &parameters.color_field {
+        Some(name) => {
+            let field = cache_entry
+                .particle_fields
+                .get(name)
+                .ok_or_else(|| anyhow!("Unknown particle field {}.", name))?;
+            Ok(Some((name.as_str(), field)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Shifts of the images of the periodic box the view reaches into, the image the camera is in
+/// first. Of the 27 images around the camera only those are taken whose shifted view
+/// overlaps `[0, box_size)³`, a view inside of one image needs a single lookup.
+fn periodic_shifts(camera_information: &CameraInfo, box_size: f64) -> Vec<[f64; 3]> {
+    let position = [
+        camera_information.x,
+        camera_information.y,
+        camera_information.z,
+    ];
+    let image = position.map(|p| (p / box_size).floor());
+    let (lower, upper) = camera_information.bounds();
+
+    let mut shifts = vec![];
+    for i in [0.0, -1.0, 1.0] {
+        for j in [0.0, -1.0, 1.0] {
+            for k in [0.0, -1.0, 1.0] {
+                let shift = [
+                    (image[0] + i) * box_size,
+                    (image[1] + j) * box_size,
+                    (image[2] + k) * box_size,
+                ];
+                if (0..3).all(|d| upper[d] - shift[d] >= 0.0 && lower[d] - shift[d] < box_size) {
+                    shifts.push(shift);
+                }
+            }
+        }
+    }
+    shifts
+}
+
+/// Leaves of the octree inside the view, each with the shift that moves its particles to
+/// where the camera sees them.
+///
+/// In a periodic box the view is looked up in the images of the box it reaches into, see
+/// `periodic_shifts`. A leaf seen in several images is returned once, in the image closest to
+/// the camera, with its origin moved into that image.
+pub fn visible_nodes(
+    octree: SharedPtr<Octree>,
+    camera_information: &CameraInfo,
+    box_size: Option<f64>,
+) -> Vec<(NodeInfo, [f64; 3])> {
+    let query = |camera: &CameraInfo| match camera.to_frustum() {
+        Some(frustum) => get_intersecting_node_info_frustum(octree.clone(), frustum),
+        None => get_intersecting_node_info(octree.clone(), camera.to_viewbox()),
+    };
+    let box_size = match box_size {
+        Some(box_size) if box_size > 0.0 => box_size,
+        _ => {
+            return query(camera_information)
+                .into_iter()
+                .map(|node| (node, [0.0; 3]))
+                .collect()
+        }
+    };
 
-use super::dto::{CameraInfo, LodResult};
+    let position = [
+        camera_information.x,
+        camera_information.y,
+        camera_information.z,
+    ];
+    let mut nodes: Vec<(NodeInfo, [f64; 3])> = vec![];
+    let mut node_positions: HashMap<i64, usize> = HashMap::new();
+    for shift in periodic_shifts(camera_information, box_size) {
+        // Moving the camera by -shift is the same as moving the box by shift.
+        let camera = CameraInfo {
+            x: position[0] - shift[0],
+            y: position[1] - shift[1],
+            z: position[2] - shift[2],
+            ..camera_information.clone()
+        };
+        for mut node in query(&camera) {
+            node.origin = RustVec3::new(
+                node.origin.x + shift[0],
+                node.origin.y + shift[1],
+                node.origin.z + shift[2],
+            );
+            match node_positions.get(&node.index) {
+                Some(&at) => {
+                    if node.distance_to(position) < nodes[at].0.distance_to(position) {
+                        nodes[at] = (node, shift);
+                    }
+                }
+                None => {
+                    node_positions.insert(node.index, nodes.len());
+                    nodes.push((node, shift));
+                }
+            }
+        }
+    }
+    nodes
+}
 
-pub fn calc_lod(
-    particle_list_of_leafs: &Array1<i64>,
-    particle_list_of_leafs_scan: &Array1<i64>,
-    splines: &Array3<f64>,
-    densities: &Array2<f64>,
-    coordinates: &Array2<f64>,
-    voronoi_diameter_extended: &Array1<f64>,
+pub fn calc_lod<F: ParticleFloat>(
+    particle_list_of_leafs: ArrayView1<i64>,
+    particle_list_of_leafs_scan: ArrayView1<i64>,
+    splines: ArrayView3<f64>,
+    densities: ArrayView2<f64>,
+    coordinates: ArrayView2<f64>,
+    voronoi_diameter_extended: ArrayView1<f64>,
+    color_field: Option<(&str, &ParticleField)>,
     octree: SharedPtr<Octree>,
     lod_batch: i64,
     camera_information: &CameraInfo,
     client_level_of_detail: &mut HashMap<i64, i64>,
+    transitioned: &HashSet<i64>,
     snapshot_id: usize,
-) -> anyhow::Result<LodResult> {
-    let node_indices = get_intersecting_node(octree, camera_information.to_viewbox());
+    options: &LodOptions,
+) -> anyhow::Result<LodResult<F>> {
+    if lod_batch <= 0 {
+        return Err(anyhow!("The batch size has to be positive."));
+    }
+    if options.density_range.is_some() && densities.is_empty() {
+        return Err(anyhow!(
+            "Filtering by density requires the densities to be loaded."
+        ));
+    }
+    if options.time.is_some() && (splines.is_empty() || densities.is_empty()) {
+        return Err(anyhow!(
+            "Interpolation requires the splines and densities to be loaded."
+        ));
+    }
+
+    let nodes = visible_nodes(octree, camera_information, options.box_size);
+    let node_indices: Vec<i64> = nodes.iter().map(|(node, _)| node.index).collect();
 
     // length of particles in leaf can be determined using the scan
     // data = [1,2,3, 4,5,6,8, 9,10,11]
@@ -31,6 +238,13 @@ pub fn calc_lod(
     //     len = scan[i+1] - scan[i]
     // else
     //     len = scan.len() - scan[i]
+    let leaf_len = |i: usize| -> i64 {
+        if i != particle_list_of_leafs_scan.len() - 1 {
+            particle_list_of_leafs_scan[i + 1] - particle_list_of_leafs_scan[i]
+        } else {
+            particle_list_of_leafs.len() as i64 - particle_list_of_leafs_scan[i]
+        }
+    };
 
     // Go over the new idx
     // if not contained within client add them with a zero
@@ -40,115 +254,462 @@ pub fn calc_lod(
         }
     }
 
-    let mut relevant_ids: Vec<i64> = vec![];
-
-    // Extract relevant particles
-    for t in &node_indices {
-        let i = (*t) as usize;
+    // Number of batches every node advances by with this request
+    let camera_position = [
+        camera_information.x,
+        camera_information.y,
+        camera_information.z,
+    ];
+    let mut node_lods: Vec<i64> = vec![];
+    let mut node_batches: Vec<i64> = vec![];
+    for (node, _) in &nodes {
         let lod = *client_level_of_detail
-            .get(t)
+            .get(&node.index)
             .context("We just inserted all keys. Something is strange")?;
+        node_lods.push(lod);
+        node_batches.push(options.policy.n_batches(node, camera_position, lod));
+    }
 
-        let len = if i != particle_list_of_leafs_scan.len() - 1 {
-            particle_list_of_leafs_scan[i + 1] - particle_list_of_leafs_scan[i]
-        } else {
-            particle_list_of_leafs.len() as i64 - particle_list_of_leafs_scan[i]
-        };
-        let start = particle_list_of_leafs_scan[i];
-        let stop = start + len;
+    // End of what every node sends, before the particle budget applies.
+    let scan_ends: Vec<usize> = node_lods
+        .iter()
+        .zip(&node_batches)
+        .map(|(lod, n_batches)| ((lod + n_batches) * lod_batch).max(0) as usize)
+        .collect();
+    // Particles of every node within the density range, up to the end of what the node sends.
+    // Leaves are only scanned until that many are found, so their lengths are lower bounds.
+    let node_particles: Option<Vec<Vec<i64>>> =
+        options.density_range.map(|(min_density, max_density)| {
+            node_indices
+                .iter()
+                .zip(&scan_ends)
+                .map(|(t, lod_end)| {
+                    let start = particle_list_of_leafs_scan[*t as usize] as usize;
+                    let stop = start + leaf_len(*t as usize) as usize;
+                    particle_list_of_leafs
+                        .slice(s![start..stop])
+                        .iter()
+                        .copied()
+                        .filter(|id| {
+                            let density = densities[[0, *id as usize]];
+                            min_density <= density && density <= max_density
+                        })
+                        .take(*lod_end)
+                        .collect()
+                })
+                .collect()
+        });
+    let node_lens: Vec<i64> = match &node_particles {
+        Some(node_particles) => node_particles
+            .iter()
+            .map(|particles| particles.len() as i64)
+            .collect(),
+        None => node_indices.iter().map(|t| leaf_len(*t as usize)).collect(),
+    };
 
-        let lod_start = min(start + lod * lod_batch, stop) as usize;
-        let lod_end = min(start + (lod + 1) * lod_batch, stop) as usize;
+    // Range every node sends, relative to the start of its particles
+    let mut node_ranges: Vec<(usize, usize)> = vec![];
+    for ((len, n_batches), lod) in node_lens.iter().zip(&node_batches).zip(&node_lods) {
+        let lod_start = min(lod * lod_batch, *len) as usize;
+        let lod_end = min((lod + n_batches) * lod_batch, *len) as usize;
+        node_ranges.push((lod_start, lod_end));
+    }
 
-        let particles = particle_list_of_leafs
-            .slice(s![lod_start..lod_end])
-            .to_vec();
+    if let Some(max_particles) = options.max_particles {
+        let priorities: Vec<NodePriority> = nodes
+            .iter()
+            .zip(&node_lods)
+            .map(|((node, _), lod)| NodePriority {
+                level_of_detail: *lod,
+                distance: node.distance_to(camera_position),
+                // Of all particles, the matches of a density range are not fully counted.
+                density: leaf_len(node.index as usize) as f64 / node.size.powi(3),
+            })
+            .collect();
+        apply_particle_budget(
+            &priorities,
+            &mut node_ranges,
+            &mut node_batches,
+            lod_batch,
+            max_particles,
+        );
+    }
+
+    let mut relevant_ids: Vec<i64> = vec![];
+    // Periodic image every particle is sent in
+    let mut relevant_shifts: Vec<[f64; 3]> = vec![];
+
+    // Extract relevant particles
+    for (n, ((lod_start, lod_end), (_, shift))) in node_ranges.iter().zip(&nodes).enumerate() {
+        let mut particles = match &node_particles {
+            Some(node_particles) => node_particles[n][*lod_start..*lod_end].to_vec(),
+            None => {
+                let start = particle_list_of_leafs_scan[node_indices[n] as usize] as usize;
+                particle_list_of_leafs
+                    .slice(s![start + lod_start..start + lod_end])
+                    .to_vec()
+            }
+        };
+        // The client already has these from a transition, the node advances all the same.
+        if !transitioned.is_empty() {
+            particles.retain(|id| !transitioned.contains(id));
+        }
+        relevant_shifts.extend(std::iter::repeat(*shift).take(particles.len()));
         relevant_ids.extend(particles);
     }
 
     // Increase relevant LODs
-    for t in &node_indices {
+    for (t, n_batches) in node_indices.iter().zip(&node_batches) {
         *client_level_of_detail
             .get_mut(t)
-            .context("Key should be contained")? += 1;
+            .context("Key should be contained")? += n_batches;
     }
 
-    let n_particles = relevant_ids.len();
-
-    // Allocate result arrays
-    // TODO unsure what is better, zero initialized and [] or with_capacity and push
-    let mut splines_a: Vec<f64> = vec![0.; 3 * n_particles];
-    let mut splines_b: Vec<f64> = vec![0.; 3 * n_particles];
-    let mut splines_c: Vec<f64> = vec![0.; 3 * n_particles];
-    let mut splines_d: Vec<f64> = vec![0.; 3 * n_particles];
-
-    // Ugly with two vectors, but do not know a real better way
-    let mut relevant_densities_flat: Vec<f64> = vec![0.0; n_particles * 2];
-
-    // let mut relevant_coordinates: Vec<Vec<f64>> = repeat_with(|| Vec::with_capacity(3)).take(n_particles).collect();
-    let mut relevant_coordinates: Vec<Vec<f64>> = vec![vec![]; n_particles];
-    let mut relevant_voronoi_diameter_extended: Vec<f64> = vec![0.0; n_particles];
-
-    // Extract relevant data and copy into result arrays
-    for (idx, id) in relevant_ids.into_iter().enumerate() {
-        splines_a.splice(
-            idx * 3..(idx + 1) * 3,
-            splines.slice(s![id as usize, 0, ..]).to_vec(),
-        );
-        splines_b.splice(
-            idx * 3..(idx + 1) * 3,
-            splines.slice(s![id as usize, 1, ..]).to_vec(),
-        );
-        splines_c.splice(
-            idx * 3..(idx + 1) * 3,
-            splines.slice(s![id as usize, 2, ..]).to_vec(),
-        );
-        splines_d.splice(
-            idx * 3..(idx + 1) * 3,
-            splines.slice(s![id as usize, 3, ..]).to_vec(),
-        );
-
-        relevant_densities_flat[idx] = densities[[0, id as usize]];
-        relevant_densities_flat[idx + n_particles] = densities[[1, id as usize]];
-
-        relevant_coordinates[idx] = coordinates.slice(s![id as usize, ..]).to_vec();
-        relevant_voronoi_diameter_extended[idx] = voronoi_diameter_extended[[id as usize]];
+    // The view is refined once no node would send anything with the next request, because
+    // all of its particles were sent or the policy gives it no more batches. A scan that
+    // stopped at its end may have missed further matches of the density range.
+    let mut refined = true;
+    for (n, (node, _)) in nodes.iter().enumerate() {
+        let lod = client_level_of_detail[&node.index];
+        let sent_all = lod * lod_batch >= node_lens[n]
+            && node_particles.as_ref().map_or(true, |node_particles| {
+                node_particles[n].len() < scan_ends[n]
+            });
+        if !sent_all && options.policy.n_batches(node, camera_position, lod) > 0 {
+            refined = false;
+            break;
+        }
     }
 
-    let (min_d, max_d) = if n_particles > 0 {
-        (
-            *relevant_densities_flat
-                .iter()
-                .min_by(|a, b| a.partial_cmp(b).expect("Failed partial cmp."))
-                .expect("Failed min iter."),
-            *relevant_densities_flat
-                .iter()
-                .max_by(|a, b| a.partial_cmp(b).expect("Failed partial cmp."))
-                .expect("Failed max iter"),
-        )
+    let n_particles = relevant_ids.len();
+
+    let fields = options
+        .fields
+        .clone()
+        .unwrap_or_else(|| LodField::ALL.to_vec());
+
+    // Positions are shifted before the conversion, so that f32 keeps its precision close to
+    // the camera.
+    let origin = if options.relative_to_camera {
+        [
+            camera_information.x,
+            camera_information.y,
+            camera_information.z,
+        ]
     } else {
-        (0.0, 0.0)
+        [0.0; 3]
     };
+    let particles = extract_particles::<F>(
+        ParticleArrays {
+            splines: splines.view(),
+            densities: densities.view(),
+            coordinates: coordinates.view(),
+            voronoi_diameter_extended: voronoi_diameter_extended.view(),
+        },
+        &relevant_ids,
+        &relevant_shifts,
+        origin,
+        &fields,
+    );
+
+    let interpolated = options.time.map(|time| {
+        interpolate_particles::<F>(
+            splines.view(),
+            densities.view(),
+            &relevant_ids,
+            &relevant_shifts,
+            origin,
+            time,
+        )
+    });
 
-    let node_indices = client_level_of_detail.keys().copied().collect();
+    let color = color_field.map(|(name, field)| color_values(name, field, &relevant_ids));
 
     Ok(LodResult {
-        splines_a,
-        splines_b,
-        splines_c,
-        splines_d,
-        relevant_densities_flat,
-        relevant_coordinates,
-        relevant_voronoi_diameter_extended,
-        client_level_of_detail: client_level_of_detail.to_owned(),
-        min_d,
-        max_d,
+        splines_a: particles.splines_a,
+        splines_b: particles.splines_b,
+        splines_c: particles.splines_c,
+        splines_d: particles.splines_d,
+        relevant_densities_flat: particles.relevant_densities_flat,
+        relevant_coordinates: particles.relevant_coordinates,
+        relevant_voronoi_diameter_extended: particles.relevant_voronoi_diameter_extended,
+        // Filled in for clients without a session, which keep track of the lod themselves.
+        client_level_of_detail: None,
+        min_d: particles.min_d,
+        max_d: particles.max_d,
         n_particles,
+        fields,
         snapshot_id,
-        node_indices,
+        node_indices: None,
+        origin: options.relative_to_camera.then_some(origin),
+        color,
+        interpolated,
+        refined,
     })
 }
 
+/// Particles per parallel task of the extraction, smaller batches stay on the calling thread.
+const MIN_PARTICLES_PER_TASK: usize = 1024;
+
+/// Views of the per-particle arrays of a snapshot.
+#[derive(Clone, Copy)]
+pub struct ParticleArrays<'a> {
+    pub splines: ArrayView3<'a, f64>,
+    pub densities: ArrayView2<'a, f64>,
+    pub coordinates: ArrayView2<'a, f64>,
+    pub voronoi_diameter_extended: ArrayView1<'a, f64>,
+}
+
+/// The particle arrays of a `LodResult`, empty for fields that were not extracted.
+pub struct ExtractedParticles<F> {
+    pub splines_a: Vec<F>,
+    pub splines_b: Vec<F>,
+    pub splines_c: Vec<F>,
+    pub splines_d: Vec<F>,
+    pub relevant_densities_flat: Vec<F>,
+    pub relevant_coordinates: Vec<Vec<F>>,
+    pub relevant_voronoi_diameter_extended: Vec<F>,
+    /// Zero if there are no particles or densities were not extracted.
+    pub min_d: f64,
+    pub max_d: f64,
+}
+
+/// Copy the arrays of `fields` for the particles `ids` into the output arrays.
+///
+/// Every output array is allocated once and filled in parallel chunks. Positions, that is
+/// the coordinates and the constant spline coefficient, are moved by the periodic `shifts`
+/// of the particles and relative to `origin`.
+pub fn extract_particles<F: ParticleFloat>(
+    arrays: ParticleArrays,
+    ids: &[i64],
+    shifts: &[[f64; 3]],
+    origin: [f64; 3],
+    fields: &[LodField],
+) -> ExtractedParticles<F> {
+    let n_particles = ids.len();
+    let position =
+        |value: f64, idx: usize, k: usize| F::from_f64(value + shifts[idx][k] - origin[k]);
+
+    let mut particles = ExtractedParticles {
+        splines_a: vec![],
+        splines_b: vec![],
+        splines_c: vec![],
+        splines_d: vec![],
+        relevant_densities_flat: vec![],
+        relevant_coordinates: vec![],
+        relevant_voronoi_diameter_extended: vec![],
+        min_d: 0.0,
+        max_d: 0.0,
+    };
+
+    if fields.contains(&LodField::Splines) {
+        let mut rows: [Vec<F>; 4] = std::array::from_fn(|_| vec![F::default(); 3 * n_particles]);
+        for (row, values) in rows.iter_mut().enumerate() {
+            values
+                .par_chunks_mut(3)
+                .with_min_len(MIN_PARTICLES_PER_TASK)
+                .enumerate()
+                .for_each(|(idx, values)| {
+                    let id = ids[idx] as usize;
+                    for (k, value) in values.iter_mut().enumerate() {
+                        let coefficient = arrays.splines[[id, row, k]];
+                        *value = if row == 0 {
+                            position(coefficient, idx, k)
+                        } else {
+                            F::from_f64(coefficient)
+                        };
+                    }
+                });
+        }
+        let [splines_a, splines_b, splines_c, splines_d] = rows;
+        particles.splines_a = splines_a;
+        particles.splines_b = splines_b;
+        particles.splines_c = splines_c;
+        particles.splines_d = splines_d;
+    }
+
+    if fields.contains(&LodField::Densities) {
+        // Both rows after each other
+        let mut densities: Vec<F> = vec![F::default(); 2 * n_particles];
+        let (current, next) = densities.split_at_mut(n_particles);
+        for (row, values) in [current, next].into_iter().enumerate() {
+            values
+                .par_iter_mut()
+                .with_min_len(MIN_PARTICLES_PER_TASK)
+                .enumerate()
+                .for_each(|(idx, value)| {
+                    *value = F::from_f64(arrays.densities[[row, ids[idx] as usize]])
+                });
+        }
+        particles.relevant_densities_flat = densities;
+
+        if n_particles > 0 {
+            (particles.min_d, particles.max_d) = ids
+                .par_iter()
+                .with_min_len(MIN_PARTICLES_PER_TASK)
+                .map(|id| {
+                    let (current, next) = (
+                        arrays.densities[[0, *id as usize]],
+                        arrays.densities[[1, *id as usize]],
+                    );
+                    (current.min(next), current.max(next))
+                })
+                .reduce(
+                    || (f64::INFINITY, f64::NEG_INFINITY),
+                    |(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)),
+                );
+        }
+    }
+
+    if fields.contains(&LodField::Coordinates) {
+        particles.relevant_coordinates = (0..n_particles)
+            .into_par_iter()
+            .with_min_len(MIN_PARTICLES_PER_TASK)
+            .map(|idx| {
+                let id = ids[idx] as usize;
+                (0..3)
+                    .map(|k| position(arrays.coordinates[[id, k]], idx, k))
+                    .collect()
+            })
+            .collect();
+    }
+
+    if fields.contains(&LodField::VoronoiDiameter) {
+        particles.relevant_voronoi_diameter_extended = ids
+            .par_iter()
+            .with_min_len(MIN_PARTICLES_PER_TASK)
+            .map(|id| F::from_f64(arrays.voronoi_diameter_extended[[*id as usize]]))
+            .collect();
+    }
+
+    particles
+}
+
+/// Values of the colouring `field` called `name` for the particles `ids`.
+pub fn color_values<F: ParticleFloat>(
+    name: &str,
+    field: &ParticleField,
+    ids: &[i64],
+) -> ColorValues<F> {
+    let scalars: Vec<f64> = ids
+        .par_iter()
+        .with_min_len(MIN_PARTICLES_PER_TASK)
+        .map(|id| field.scalar(*id as usize))
+        .collect();
+    let (min, max) = if scalars.is_empty() {
+        (0.0, 0.0)
+    } else {
+        scalars
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+                (min.min(*value), max.max(*value))
+            })
+    };
+    ColorValues {
+        field: name.to_string(),
+        values: scalars.into_iter().map(F::from_f64).collect(),
+        min,
+        max,
+    }
+}
+
+/// Positions and densities of the particles `ids` at `time` between this snapshot (0) and the
+/// next (1).
+///
+/// The positions evaluate the cubic splines `a + b t + c t^2 + d t^3`, moved by the periodic
+/// `shifts` and relative to `origin` like the extracted positions. The densities are
+/// interpolated linearly between both rows.
+pub fn interpolate_particles<F: ParticleFloat>(
+    splines: ArrayView3<f64>,
+    densities: ArrayView2<f64>,
+    ids: &[i64],
+    shifts: &[[f64; 3]],
+    origin: [f64; 3],
+    time: f64,
+) -> InterpolatedValues<F> {
+    let mut positions: Vec<F> = vec![F::default(); 3 * ids.len()];
+    positions
+        .par_chunks_mut(3)
+        .with_min_len(MIN_PARTICLES_PER_TASK)
+        .enumerate()
+        .for_each(|(idx, values)| {
+            let id = ids[idx] as usize;
+            for (k, value) in values.iter_mut().enumerate() {
+                let position = splines[[id, 0, k]]
+                    + time
+                        * (splines[[id, 1, k]]
+                            + time * (splines[[id, 2, k]] + time * splines[[id, 3, k]]));
+                *value = F::from_f64(position + shifts[idx][k] - origin[k]);
+            }
+        });
+    let densities = ids
+        .par_iter()
+        .with_min_len(MIN_PARTICLES_PER_TASK)
+        .map(|id| {
+            let id = *id as usize;
+            F::from_f64((1.0 - time) * densities[[0, id]] + time * densities[[1, id]])
+        })
+        .collect();
+    InterpolatedValues {
+        time,
+        positions,
+        densities,
+    }
+}
+
+/// What decides which node gets particles first if the budget does not suffice for all.
+struct NodePriority {
+    level_of_detail: i64,
+    distance: f64,
+    /// Particles per volume of the node.
+    density: f64,
+}
+
+/// Cut the particle ranges of the nodes down to at most `max_particles` particles together.
+///
+/// The budget is handed out in whole batches, first to the least refined nodes, among equally
+/// refined ones to the closest and then to the densest. Nodes that receive nothing keep their
+/// level of detail. The first batch is always sent, even if it exceeds a budget smaller than
+/// `lod_batch`, so that the client never gets stuck.
+fn apply_particle_budget(
+    priorities: &[NodePriority],
+    node_ranges: &mut [(usize, usize)],
+    node_batches: &mut [i64],
+    lod_batch: i64,
+    max_particles: usize,
+) {
+    let mut order: Vec<usize> = (0..priorities.len()).collect();
+    order.sort_by(|a, b| {
+        let (a, b) = (&priorities[*a], &priorities[*b]);
+        a.level_of_detail
+            .cmp(&b.level_of_detail)
+            .then(a.distance.total_cmp(&b.distance))
+            .then(b.density.total_cmp(&a.density))
+    });
+
+    let mut remaining = max_particles;
+    for i in order {
+        let (lod_start, lod_end) = node_ranges[i];
+        let available_batches = ((lod_end - lod_start) as i64 + lod_batch - 1) / lod_batch;
+        let mut granted = min(available_batches, (remaining / lod_batch as usize) as i64);
+        if granted == 0 && available_batches > 0 && remaining == max_particles {
+            granted = 1;
+        }
+
+        let end = min(lod_start + (granted * lod_batch) as usize, lod_end);
+        node_ranges[i] = (lod_start, end);
+        node_batches[i] = granted;
+        remaining = remaining.saturating_sub(end - lod_start);
+    }
+}
+
+/// What `benches/extraction.rs` needs, not part of the API of the crate.
+#[doc(hidden)]
+pub mod bench_support {
+    pub use super::{extract_particles, ExtractedParticles, ParticleArrays};
+    pub use crate::dto::{LodField, ParticleFloat};
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::bind::ffi::load_octree_from_file;
@@ -165,6 +726,65 @@ mod tests {
         assert_eq!(data.len(), 10);
     }
 
+    #[test]
+    fn test_lod_options_require_loaded_fields() {
+        let entry = CacheEntry {
+            particle_list_of_leafs: Array::zeros(0).into(),
+            particle_list_of_leafs_scan: Array::zeros(0).into(),
+            splines: Array::zeros((0, 4, 3)).into(),
+            densities: Array::zeros((0, 0)).into(),
+            quantiles: array![0.0, 1.0].into(),
+            coordinates: Array::zeros((0, 3)).into(),
+            voronoi_diameter_extended: Array::zeros(0).into(),
+            octree: SharedPtr::null(),
+            snapdir: None,
+            fingerprint: Default::default(),
+            box_size: None,
+            fields: vec![LodField::Splines, LodField::Coordinates],
+            particle_fields: HashMap::new(),
+            particle_ids: Array::zeros(0).into(),
+        };
+        let parameters = |json: &str| -> LodParameters {
+            let camera = r#""camera_information": {"x": 0.0, "y": 0.0, "z": 0.0, "size": 1.0}"#;
+            serde_json::from_str(&format!(
+                r#"{{"batch_size_lod": 10, {}, {}}}"#,
+                camera, json
+            ))
+            .unwrap()
+        };
+
+        let options =
+            LodOptions::new(&parameters(r#""relative_to_camera": true"#), &entry).unwrap();
+        assert_eq!(options.fields, Some(entry.fields.clone()));
+        assert!(LodOptions::new(&parameters(r#""fields": ["densities"]"#), &entry).is_err());
+        assert!(LodOptions::new(&parameters(r#""min_density": 0.5"#), &entry).is_err());
+        assert!(LodOptions::new(&parameters(r#""time": 0.5"#), &entry).is_err());
+    }
+
+    #[test]
+    fn test_periodic_shifts() {
+        let camera = |x: f64, size: f64| CameraInfo {
+            x,
+            y: 50.0,
+            z: 50.0,
+            size,
+            ..Default::default()
+        };
+        // Inside of the box, in the image the camera is in and in the next image.
+        assert_eq!(periodic_shifts(&camera(50.0, 10.0), 100.0), vec![[0.0; 3]]);
+        assert_eq!(
+            periodic_shifts(&camera(150.0, 10.0), 100.0),
+            vec![[100.0, 0.0, 0.0]]
+        );
+        // Across the lower x boundary.
+        assert_eq!(
+            periodic_shifts(&camera(2.0, 10.0), 100.0),
+            vec![[0.0; 3], [-100.0, 0.0, 0.0]]
+        );
+        // Across all boundaries.
+        assert_eq!(periodic_shifts(&camera(50.0, 120.0), 100.0).len(), 27);
+    }
+
     // Test will currently fail because we do not know what the octree traversal returns
     #[test]
     fn test_calc_lod_stuff() {
@@ -181,6 +801,8 @@ mod tests {
             Array::from_shape_fn((2, particle_list_of_leafs.len()), |(_i, j)| (j + 1) as f64);
         let coordinates: Array2<f64> =
             Array::from_shape_fn((particle_list_of_leafs.len(), 3), |(_i, j)| (j + 1) as f64);
+        let voronoi_diameter_extended: Array1<f64> =
+            Array::from_shape_fn(particle_list_of_leafs.len(), |i| (i + 1) as f64);
 
         let lod_batch = 2;
         let mut client_level_of_detail = HashMap::new();
@@ -202,30 +824,153 @@ mod tests {
             y: 2.0,
             z: 3.0,
             size: 4.0,
+            ..Default::default()
         };
-        let res = calc_lod(
-            &particle_list_of_leafs,
-            &particle_list_of_leafs_scan,
-            &splines,
-            &densities,
-            &coordinates,
+        let res = calc_lod::<f64>(
+            particle_list_of_leafs.view(),
+            particle_list_of_leafs_scan.view(),
+            splines.view(),
+            densities.view(),
+            coordinates.view(),
+            voronoi_diameter_extended.view(),
+            None,
             octree.clone(),
             lod_batch,
             &camera_information,
             &mut client_level_of_detail,
-        );
+            &HashSet::new(),
+            0,
+            &LodOptions::default(),
+        )
+        .unwrap();
 
-        let mut keys: Vec<i64> = res.client_level_of_detail.keys().copied().collect();
+        let mut keys: Vec<i64> = client_level_of_detail.keys().copied().collect();
         keys.sort();
 
         assert_eq!(vec![0, 1, 2, 3, 4], keys);
 
-        assert_eq!(1, *res.client_level_of_detail.get(&0).unwrap());
-        assert_eq!(2, *res.client_level_of_detail.get(&1).unwrap());
-        assert_eq!(1, *res.client_level_of_detail.get(&2).unwrap());
-        assert_eq!(2, *res.client_level_of_detail.get(&3).unwrap());
-        assert_eq!(1, *res.client_level_of_detail.get(&4).unwrap());
+        assert_eq!(1, *client_level_of_detail.get(&0).unwrap());
+        assert_eq!(2, *client_level_of_detail.get(&1).unwrap());
+        assert_eq!(1, *client_level_of_detail.get(&2).unwrap());
+        assert_eq!(2, *client_level_of_detail.get(&3).unwrap());
+        assert_eq!(1, *client_level_of_detail.get(&4).unwrap());
 
         assert_eq!(7, res.n_particles)
     }
+
+    #[test]
+    fn test_particle_budget() {
+        let priority = |level_of_detail, distance, density| NodePriority {
+            level_of_detail,
+            distance,
+            density,
+        };
+        let priorities = [
+            priority(1, 0.0, 1.0),
+            priority(0, 5.0, 1.0),
+            priority(0, 1.0, 1.0),
+            priority(0, 1.0, 2.0),
+        ];
+        let mut node_ranges = [(0, 4), (10, 14), (20, 23), (30, 34)];
+        let mut node_batches = [2, 2, 2, 2];
+
+        apply_particle_budget(&priorities, &mut node_ranges, &mut node_batches, 2, 7);
+
+        // The closest unrefined nodes come first, the dense one before the sparse one.
+        assert_eq!([(0, 0), (10, 10), (20, 22), (30, 34)], node_ranges);
+        assert_eq!([0, 0, 1, 2], node_batches);
+
+        // A budget below one batch still sends the first batch.
+        let mut node_ranges = [(0, 4), (10, 14)];
+        let mut node_batches = [2, 2];
+        apply_particle_budget(&priorities[..2], &mut node_ranges, &mut node_batches, 2, 1);
+        assert_eq!([(0, 0), (10, 12)], node_ranges);
+        assert_eq!([0, 1], node_batches);
+    }
+
+    #[test]
+    fn test_extract_particles() {
+        let n = 3000;
+        let splines: Array3<f64> =
+            Array::from_shape_fn((n, 4, 3), |(i, j, k)| (i * 12 + j * 3 + k) as f64);
+        let densities: Array2<f64> = Array::from_shape_fn((2, n), |(i, j)| (j + i * n) as f64);
+        let coordinates: Array2<f64> = Array::from_shape_fn((n, 3), |(i, j)| (i * 3 + j) as f64);
+        let voronoi_diameter_extended: Array1<f64> = Array::from_shape_fn(n, |i| i as f64);
+        let arrays = ParticleArrays {
+            splines: splines.view(),
+            densities: densities.view(),
+            coordinates: coordinates.view(),
+            voronoi_diameter_extended: voronoi_diameter_extended.view(),
+        };
+        let ids: Vec<i64> = (0..n as i64).rev().step_by(2).collect();
+        let shifts = vec![[10.0, 0.0, 0.0]; ids.len()];
+
+        let particles =
+            extract_particles::<f64>(arrays, &ids, &shifts, [1.0, 1.0, 1.0], &LodField::ALL);
+        let m = ids.len();
+        for (idx, id) in ids.iter().map(|id| *id as usize).enumerate() {
+            assert_eq!(particles.splines_a[idx * 3], splines[[id, 0, 0]] + 9.0);
+            assert_eq!(particles.splines_a[idx * 3 + 2], splines[[id, 0, 2]] - 1.0);
+            assert_eq!(particles.splines_d[idx * 3 + 1], splines[[id, 3, 1]]);
+            assert_eq!(particles.relevant_densities_flat[idx], densities[[0, id]]);
+            assert_eq!(
+                particles.relevant_densities_flat[m + idx],
+                densities[[1, id]]
+            );
+            assert_eq!(
+                particles.relevant_coordinates[idx],
+                vec![
+                    coordinates[[id, 0]] + 9.0,
+                    coordinates[[id, 1]] - 1.0,
+                    coordinates[[id, 2]] - 1.0
+                ]
+            );
+            assert_eq!(particles.relevant_voronoi_diameter_extended[idx], id as f64);
+        }
+        assert_eq!(particles.min_d, 1.0);
+        assert_eq!(particles.max_d, (2 * n - 1) as f64);
+
+        let particles = extract_particles::<f32>(
+            arrays,
+            &ids,
+            &shifts,
+            [0.0; 3],
+            &[LodField::VoronoiDiameter],
+        );
+        assert!(particles.splines_a.is_empty() && particles.relevant_coordinates.is_empty());
+        assert_eq!(particles.relevant_voronoi_diameter_extended.len(), m);
+    }
+
+    #[test]
+    fn test_interpolate_particles() {
+        // Particle 1 moves along x(t) = 1 + t + t^2 + t^3.
+        let splines: Array3<f64> =
+            Array::from_shape_fn((2, 4, 3), |(i, _j, k)| if k == 0 { i as f64 } else { 0.0 });
+        let densities = array![[1.0, 2.0], [3.0, 6.0]];
+        let ids = [1];
+        let shifts = [[10.0, 0.0, 0.0]];
+
+        let start = interpolate_particles::<f64>(
+            splines.view(),
+            densities.view(),
+            &ids,
+            &shifts,
+            [0.0; 3],
+            0.0,
+        );
+        assert_eq!(start.positions, vec![11.0, 0.0, 0.0]);
+        assert_eq!(start.densities, vec![2.0]);
+
+        let halfway = interpolate_particles::<f32>(
+            splines.view(),
+            densities.view(),
+            &ids,
+            &shifts,
+            [1.0, 1.0, 1.0],
+            0.5,
+        );
+        assert_eq!(halfway.positions, vec![10.875, -1.0, -1.0]);
+        assert_eq!(halfway.densities, vec![4.0]);
+        assert_eq!(halfway.time, 0.5);
+    }
 }
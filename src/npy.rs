@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::path::Path;
+
+use memmap2::Mmap;
+use ndarray::{Array, ArrayView, Dimension};
+use ndarray_npy::{read_npy, ReadableElement, ViewElement, ViewNpyExt};
+
+use anyhow::Context;
+
+use super::dto::NpyLoadMode;
+
+/// An array loaded from an `.npy` file, either copied into heap memory or mapped from disk.
+///
+/// Mapped arrays are backed by the OS page cache, so they are available right after opening
+/// and their pages are shared between processes and survive restarts.
+pub enum NpyArray<A, D> {
+    Owned(Array<A, D>),
+    Mapped(Mmap, PhantomData<(A, D)>),
+}
+
+impl<A, D> NpyArray<A, D>
+where
+    A: ReadableElement + ViewElement,
+    D: Dimension,
+{
+    pub fn open<P: AsRef<Path>>(path: P, mode: NpyLoadMode) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        match mode {
+            NpyLoadMode::Read => {
+                Ok(NpyArray::Owned(read_npy(path).with_context(|| {
+                    format!("Failed to read {}", path.display())
+                })?))
+            }
+            NpyLoadMode::Mmap => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                // Safety: the snapshot files are treated as read only. Modifying them while
+                // they are mapped is undefined behaviour, which is why the preprocessing has to
+                // write new files instead of changing existing ones in place.
+                let mmap = unsafe { Mmap::map(&file) }
+                    .with_context(|| format!("Failed to mmap {}", path.display()))?;
+                // Validate header, type and layout once so that `view` can not fail later on.
+                ArrayView::<A, D>::view_npy(&mmap)
+                    .with_context(|| format!("Failed to view {}", path.display()))?;
+                Ok(NpyArray::Mapped(mmap, PhantomData))
+            }
+        }
+    }
+}
+
+impl<A: ViewElement, D: Dimension> NpyArray<A, D> {
+    pub fn view(&self) -> ArrayView<'_, A, D> {
+        match self {
+            NpyArray::Owned(array) => array.view(),
+            NpyArray::Mapped(mmap, _) => {
+                ArrayView::view_npy(mmap).expect("The npy header was validated when opening.")
+            }
+        }
+    }
+
+    /// Heap memory held by the array. Mapped arrays live in the page cache and count as zero.
+    #[cfg(test)]
+    pub fn heap_size_in_bytes(&self) -> usize {
+        match self {
+            NpyArray::Owned(array) => array.len() * size_of::<A>(),
+            NpyArray::Mapped(..) => 0,
+        }
+    }
+
+    /// Memory the array occupies, the length of the mapping for mapped arrays. Mapped pages
+    /// are only evicted from the page cache under pressure, so they count against the budget.
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            NpyArray::Owned(array) => array.len() * size_of::<A>(),
+            NpyArray::Mapped(mmap, _) => mmap.len(),
+        }
+    }
+}
+
+impl<A, D> From<Array<A, D>> for NpyArray<A, D> {
+    fn from(array: Array<A, D>) -> Self {
+        NpyArray::Owned(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Array, Array2, Ix2};
+    use ndarray_npy::write_npy;
+
+    #[test]
+    fn test_read_and_mmap_agree() {
+        let path = std::env::temp_dir().join("cache_server_npy_test.npy");
+        let array: Array2<f64> = Array::from_shape_fn((5, 3), |(i, j)| (i * 3 + j) as f64);
+        write_npy(&path, &array).unwrap();
+
+        let read = NpyArray::<f64, Ix2>::open(&path, NpyLoadMode::Read).unwrap();
+        let mapped = NpyArray::<f64, Ix2>::open(&path, NpyLoadMode::Mmap).unwrap();
+
+        assert_eq!(read.view(), array.view());
+        assert_eq!(mapped.view(), array.view());
+        assert_eq!(read.heap_size_in_bytes(), 15 * 8);
+        assert_eq!(mapped.heap_size_in_bytes(), 0);
+        assert!(mapped.size_in_bytes() > 15 * 8);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
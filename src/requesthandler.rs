@@ -1,19 +1,22 @@
 use actix::*;
-use actix_web::error::ErrorInternalServerError;
+use actix_web::error::{
+    ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized,
+};
 use actix_web::{
+    http::header,
     rt::time::{sleep_until, Instant},
-    web, Error, Responder,
+    web, Error, HttpRequest, HttpResponse, Responder,
 };
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
-use std::str::FromStr;
-
-use regex::Regex;
+use actix_web_actors::ws;
 
-use hdf5::File;
-
-use super::{data_cache, dto, lod, utils};
+use super::dto::ParticleFloat;
+use super::{binary, compression, data_cache, dto, lod, session, stream, transition, warmup};
 use anyhow::{anyhow, Context};
+use sha2::{Digest, Sha256};
 
 pub async fn get_rand_init(cache: web::Data<Addr<data_cache::DataCache>>) -> Result<String, Error> {
     sleep_until(Instant::now() + Duration::from_secs(0)).await;
@@ -24,58 +27,252 @@ pub async fn get_rand_init(cache: web::Data<Addr<data_cache::DataCache>>) -> Res
     }
 }
 
+/// Run the lod calculation with the precision `F` on a worker thread and encode the result as
+/// the client asked. With a `transition`, the previous snapshot and the level of detail of the
+/// client there, the particles of the transition are sent instead and recorded in
+/// `transitioned`. Returns the response with the updated `level_of_detail` and `transitioned`.
+async fn lod_response<F: ParticleFloat>(
+    req: &HttpRequest,
+    cache_entry: Arc<data_cache::CacheEntry>,
+    transition: Option<(Arc<data_cache::CacheEntry>, HashMap<i64, i64>)>,
+    client_state: &dto::ClientState,
+    mut level_of_detail: HashMap<i64, i64>,
+    mut transitioned: HashSet<i64>,
+    snapshot_id: usize,
+    cfg: &dto::WebServiceConfig,
+) -> Result<(HttpResponse, HashMap<i64, i64>, HashSet<i64>), Error> {
+    let parameters = client_state.parameters.clone();
+    let with_session = client_state.session_id.is_some();
+    // Bad requests are rejected before the calculation is handed to a worker thread.
+    let options = lod::LodOptions::new(&parameters, &cache_entry).map_err(ErrorBadRequest)?;
+    lod::color_field(&parameters, &cache_entry).map_err(ErrorBadRequest)?;
+    let (lod_result, level_of_detail, transitioned) = web::block(move || {
+        let color_field = lod::color_field(&parameters, &cache_entry)?;
+        let lod_result = match &transition {
+            Some((previous, previous_level_of_detail)) => transition::calc_transition::<F>(
+                previous,
+                previous_level_of_detail,
+                &cache_entry,
+                color_field,
+                parameters.batch_size_lod,
+                &parameters.camera_information,
+                &mut transitioned,
+                snapshot_id,
+                &options,
+            ),
+            None => lod::calc_lod::<F>(
+                cache_entry.particle_list_of_leafs.view(),
+                cache_entry.particle_list_of_leafs_scan.view(),
+                cache_entry.splines.view(),
+                cache_entry.densities.view(),
+                cache_entry.coordinates.view(),
+                cache_entry.voronoi_diameter_extended.view(),
+                color_field,
+                cache_entry.octree.clone(),
+                parameters.batch_size_lod,
+                &parameters.camera_information,
+                &mut level_of_detail,
+                &transitioned,
+                snapshot_id,
+                &options,
+            ),
+        };
+        let mut lod_result = lod_result?;
+
+        // Clients without a session keep track of the level of detail themselves.
+        if !with_session {
+            lod_result.node_indices = Some(level_of_detail.keys().copied().collect());
+            lod_result.client_level_of_detail = Some(level_of_detail.clone());
+        }
+        anyhow::Ok((lod_result, level_of_detail, transitioned))
+    })
+    .await
+    .map_err(|err| ErrorInternalServerError(format!("Lod calculation was cancelled. {:?}", err)))?
+    .map_err(|err| {
+        ErrorInternalServerError(format!("Failed to calculate lod result: {:?}", err))
+    })?;
+
+    let response = if binary::accepts_binary(req) {
+        // Shuffling only pays off if the response is going to be compressed, small responses
+        // are sent as they are.
+        let shuffle = cfg.compression.shuffle
+            && compression::compresses(req, &cfg.compression, binary::encoded_len(&lod_result));
+        HttpResponse::Ok()
+            .content_type(binary::CONTENT_TYPE)
+            .body(binary::encode(&lod_result, shuffle))
+    } else {
+        HttpResponse::Ok().json(lod_result)
+    };
+    Ok((response, level_of_detail, transitioned))
+}
+
 pub async fn get_snapshot(
+    req: HttpRequest,
     params: web::Path<(String, usize)>,
-    mut client_state: web::Json<dto::ClientState>,
+    client_state: web::Json<dto::ClientState>,
     cache: web::Data<Addr<data_cache::DataCache>>,
+    sessions: web::Data<Mutex<session::SessionStore>>,
+    cfg: web::Data<dto::WebServiceConfig>,
 ) -> Result<impl Responder, Error> {
+    let mut client_state = client_state.into_inner();
+    let level_of_detail = std::mem::take(&mut client_state.level_of_detail);
     let (simulation, snapshot_id) = (params.0.clone(), params.1);
     let message = data_cache::CacheRequest {
         simulation: simulation.to_string(),
         snapshot_id,
     };
-    match cache.send(message).await {
-        Ok(cache_entry) => match cache_entry {
-            Ok(cache_entry) => {
-                let cache_entry = &*cache_entry;
-                let lod_result = lod::calc_lod(
-                    &cache_entry.particle_list_of_leafs,
-                    &cache_entry.particle_list_of_leafs_scan,
-                    &cache_entry.splines,
-                    &cache_entry.densities,
-                    &cache_entry.coordinates,
-                    &cache_entry.voronoi_diameter_extended,
-                    cache_entry.octree.clone(),
-                    client_state.batch_size_lod,
-                    &client_state.camera_information.clone(),
-                    &mut client_state.level_of_detail,
-                    snapshot_id,
-                );
-                match lod_result {
-                    Ok(lod_result) => Ok(web::Json(lod_result)),
-                    Err(err) => Err(ErrorInternalServerError(format!(
-                        "Failed to calculate lod result: {:?}",
-                        err
-                    ))),
-                }
-            }
-            Err(err) => Err(ErrorInternalServerError(format!(
-                "Data loading failed. {:?}",
-                err
-            ))),
-        },
-        Err(err) => Err(ErrorInternalServerError(format!(
-            "Communication with data cache failed. {:?}",
-            err
-        ))),
+    let session = match &client_state.session_id {
+        Some(session_id) => Some(
+            sessions
+                .lock()
+                .map_err(|_| ErrorInternalServerError("Session store lock is poisoned."))?
+                .get(session_id)
+                .ok_or_else(|| ErrorNotFound("Unknown or expired session, call init again."))?,
+        ),
+        None => None,
+    };
+    // The snapshot the client switches from. It is not loaded for the transition, that could
+    // evict the requested one, without it the view is refined as usual.
+    let previous = match &client_state.transition {
+        Some(transition) => cache
+            .send(data_cache::PeekRequest {
+                simulation: simulation.clone(),
+                snapshot_id: transition.snapshot_id,
+            })
+            .await
+            .map_err(|err| {
+                ErrorInternalServerError(format!("Communication with data cache failed. {:?}", err))
+            })?,
+        None => None,
+    };
+    let cache_entry = cache
+        .send(message.clone())
+        .await
+        .map_err(|err| {
+            ErrorInternalServerError(format!("Communication with data cache failed. {:?}", err))
+        })?
+        .map_err(|err| ErrorInternalServerError(format!("Data loading failed. {:?}", err)))?;
+
+    // Copy what the lod calculation needs out of the session, so that it is not locked while
+    // the calculation runs.
+    let (previous_level_of_detail, level_of_detail, transitioned) = match &session {
+        Some(session) => {
+            let session = session
+                .lock()
+                .map_err(|_| ErrorInternalServerError("Session lock is poisoned."))?;
+            let previous_level_of_detail = match &client_state.transition {
+                Some(transition) => session
+                    .level_of_detail
+                    .get(&data_cache::CacheRequest {
+                        simulation: simulation.clone(),
+                        snapshot_id: transition.snapshot_id,
+                    })
+                    .cloned()
+                    .unwrap_or_default(),
+                None => HashMap::new(),
+            };
+            (
+                previous_level_of_detail,
+                session
+                    .level_of_detail
+                    .get(&message)
+                    .cloned()
+                    .unwrap_or_default(),
+                session
+                    .transitioned
+                    .get(&message)
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+        }
+        // Without a session the transitioned particles are not remembered.
+        None => (
+            client_state
+                .transition
+                .as_ref()
+                .map(|transition| transition.level_of_detail.clone())
+                .unwrap_or_default(),
+            level_of_detail,
+            HashSet::new(),
+        ),
+    };
+    let transition = previous.map(|previous| (previous, previous_level_of_detail));
+
+    let (response, level_of_detail, transitioned) = match client_state.parameters.precision {
+        dto::Precision::F32 => {
+            lod_response::<f32>(
+                &req,
+                cache_entry,
+                transition,
+                &client_state,
+                level_of_detail,
+                transitioned,
+                snapshot_id,
+                &cfg,
+            )
+            .await?
+        }
+        dto::Precision::F64 => {
+            lod_response::<f64>(
+                &req,
+                cache_entry,
+                transition,
+                &client_state,
+                level_of_detail,
+                transitioned,
+                snapshot_id,
+                &cfg,
+            )
+            .await?
+        }
+    };
+
+    // Merge the progress back, of concurrent requests of the same session for the same
+    // snapshot the last one wins.
+    if let (Some(session_id), Some(session)) = (&client_state.session_id, session) {
+        let size_in_bytes = {
+            let mut session = session
+                .lock()
+                .map_err(|_| ErrorInternalServerError("Session lock is poisoned."))?;
+            session
+                .level_of_detail
+                .insert(message.clone(), level_of_detail);
+            session.transitioned.insert(message, transitioned);
+            session.size_in_bytes()
+        };
+        sessions
+            .lock()
+            .map_err(|_| ErrorInternalServerError("Session store lock is poisoned."))?
+            .update_size(session_id, size_in_bytes);
     }
+    Ok(response)
+}
+
+/// Upgrade to a WebSocket that streams lod batches, see `stream::LodStream`.
+pub async fn stream_lod(
+    req: HttpRequest,
+    params: web::Path<(String, usize)>,
+    payload: web::Payload,
+    cache: web::Data<Addr<data_cache::DataCache>>,
+) -> Result<HttpResponse, Error> {
+    let request = data_cache::CacheRequest {
+        simulation: params.0.clone(),
+        snapshot_id: params.1,
+    };
+    ws::start(
+        stream::LodStream::new(cache.get_ref().clone(), request),
+        &req,
+        payload,
+    )
 }
 
 pub async fn get_init(
     params: web::Path<(String, usize)>,
+    query: web::Query<dto::InitQuery>,
     cache: web::Data<Addr<data_cache::DataCache>>,
+    sessions: web::Data<Mutex<session::SessionStore>>,
 ) -> Result<impl Responder, Error> {
-    match _get_init(params, cache).await {
+    match _get_init(params, query, cache, sessions).await {
         Ok(result) => Ok(result),
         Err(err) => Err(ErrorInternalServerError(format!(
             "Failed to calculate lod result: {:?}",
@@ -85,7 +282,9 @@ pub async fn get_init(
 }
 pub async fn _get_init(
     params: web::Path<(String, usize)>,
+    query: web::Query<dto::InitQuery>,
     cache: web::Data<Addr<data_cache::DataCache>>,
+    sessions: web::Data<Mutex<session::SessionStore>>,
 ) -> anyhow::Result<web::Json<dto::InitResponse>> {
     let (simulation, snapshot_id) = (params.0.clone(), params.1);
     let message = data_cache::CacheRequest {
@@ -94,71 +293,65 @@ pub async fn _get_init(
     };
 
     // Find out which snapdirs exist for this simulation
-    let base = cache
-        .send(data_cache::BaseDirRequest {})
+    let source = cache
+        .send(data_cache::SnapshotSourceRequest {
+            simulation: simulation.clone(),
+        })
         .await
-        .context("Failed to get configured basedir.")?;
-    let basedir = base.clone() + "/" + &simulation + "/";
-    let regex = Regex::new("snapdir_.*").context("Failed to generate regex.")?;
+        .context("Failed to get the snapshot source.")?;
 
     // For the first assume that the groupcat exists and get the box size info
-    let matching_folders = utils::search_folders_matching_regex(basedir, &regex)
-        .context("Failed to query for snapdirs")?;
-    if matching_folders.len() == 0 {
-        Err(anyhow!("No snapdirs found."))
-    } else {
-        let all_possible_snaps: Vec<usize> = matching_folders
-            .iter()
-            .into_iter()
-            .map(|folder| {
-                usize::from_str(
-                    &folder
-                        .file_name()
-                        .expect("Failed to get filename.")
-                        .to_string_lossy()
-                        .replace("snapdir_", ""),
-                )
-                .expect("Failed to convert to usize")
-            })
-            .collect();
-        let snap_id = all_possible_snaps
-            .first()
-            .context("Could not get first snap_id.")?;
-        let groupcat = base.clone()
-            + "/"
-            + &simulation
-            + "/"
-            + &format!("groups_{:03}", snap_id)
-            + "/"
-            + &format!("fof_subhalo_tab_{:03}.0.hdf5", snap_id);
-
-        let file = File::open(groupcat).context("Failed to open groupcat")?;
-        let header = file
-            .group("Header")
-            .context("Failed to access header group")?;
-        let attribute = header
-            .attr("BoxSize")
-            .context("Failed to access box size attribute.")?;
-        let box_size = attribute
-            .read_scalar::<usize>()
-            .context("Failed te read scalar.")?;
-
-        match cache.send(message).await {
-            Ok(cache_entry) => match cache_entry {
-                Ok(cache_entry) => {
-                    let cache_entry = &*cache_entry;
-                    let init_response = dto::InitResponse {
-                        all_possible_snaps,
-                        box_size,
-                        quantiles: cache_entry.quantiles.to_vec(),
-                        n_quantiles: cache_entry.quantiles.len(),
-                    };
-                    Ok(web::Json(init_response))
-                }
-                Err(err) => Err(anyhow!("Data loading failed: {:?}.", err)),
-            },
-            Err(err) => Err(anyhow!("Communication with data cache failed: {:?}.", err)),
-        }
+    let (all_possible_snaps, box_size) = web::block(move || -> anyhow::Result<_> {
+        let all_possible_snaps = source.snapshot_ids(&simulation)?;
+        let snap_id = all_possible_snaps.first().context("No snapdirs found.")?;
+        let box_size = source.box_size(&simulation, *snap_id)?;
+        Ok((all_possible_snaps, box_size))
+    })
+    .await
+    .context("Failed to query the snapshot source.")??;
+
+    match cache.send(message).await {
+        Ok(cache_entry) => match cache_entry {
+            Ok(cache_entry) => {
+                // Quantiles that were not precomputed are computed by the first init.
+                let entry = cache_entry.clone();
+                let mut fields = web::block(move || -> Vec<dto::ParticleFieldInfo> {
+                    entry
+                        .particle_fields
+                        .iter()
+                        .map(|(name, field)| dto::ParticleFieldInfo {
+                            name: name.clone(),
+                            components: field.components(),
+                            quantiles: field.quantiles().to_vec(),
+                        })
+                        .collect()
+                })
+                .await
+                .context("Failed to compute the quantiles of the particle fields.")?;
+                fields.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+                let cache_entry = &*cache_entry;
+                let session_id = {
+                    let mut sessions = sessions
+                        .lock()
+                        .map_err(|_| anyhow!("Session store lock is poisoned."))?;
+                    match query.into_inner().session_id {
+                        Some(session_id) if sessions.get(&session_id).is_some() => session_id,
+                        _ => sessions.create(),
+                    }
+                };
+                let init_response = dto::InitResponse {
+                    all_possible_snaps,
+                    box_size,
+                    quantiles: cache_entry.quantiles.view().to_vec(),
+                    n_quantiles: cache_entry.quantiles.view().len(),
+                    session_id,
+                    fields,
+                };
+                Ok(web::Json(init_response))
+            }
+            Err(err) => Err(anyhow!("Data loading failed: {:?}.", err)),
+        },
+        Err(err) => Err(anyhow!("Communication with data cache failed: {:?}.", err)),
     }
 }
 
@@ -180,3 +373,204 @@ pub async fn get_current_cache(
         ))
     }
 }
+
+/// Readiness of the server. Responds with 503 until the configured warm set is loaded.
+pub async fn get_status(
+    status: web::Data<RwLock<warmup::WarmupStatus>>,
+) -> Result<impl Responder, Error> {
+    let status = status
+        .read()
+        .map_err(|_| ErrorInternalServerError("Warmup status lock is poisoned."))?
+        .clone();
+    if status.ready {
+        Ok(HttpResponse::Ok().json(status))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(status))
+    }
+}
+
+/// Compare the digests of both tokens without short-circuiting, so that neither the response
+/// time nor the digest length leaks anything about the token.
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    Sha256::digest(expected.as_bytes())
+        .iter()
+        .zip(Sha256::digest(provided.as_bytes()).iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+fn check_admin_token(req: &HttpRequest, cfg: &dto::WebServiceConfig) -> Result<(), Error> {
+    let expected = match &cfg.admin_token {
+        Some(token) => token,
+        None => {
+            return Err(ErrorForbidden(
+                "Cache administration is disabled, no admin_token is configured.",
+            ))
+        }
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if tokens_match(expected, provided) => Ok(()),
+        _ => Err(ErrorUnauthorized("Missing or invalid admin token.")),
+    }
+}
+
+pub async fn load_cache_entry(
+    req: HttpRequest,
+    params: web::Path<(String, usize)>,
+    cache: web::Data<Addr<data_cache::DataCache>>,
+    cfg: web::Data<dto::WebServiceConfig>,
+) -> Result<impl Responder, Error> {
+    check_admin_token(&req, &cfg)?;
+    let (simulation, snapshot_id) = (params.0.clone(), params.1);
+    let message = data_cache::LoadRequest {
+        simulation: simulation.clone(),
+        snapshot_id,
+    };
+    match cache.send(message).await {
+        Ok(Ok(size_in_bytes)) => Ok(web::Json(dto::CacheLoadResponse {
+            simulation,
+            snapshot_id,
+            size_in_bytes,
+        })),
+        Ok(Err(err)) => Err(ErrorInternalServerError(format!(
+            "Data loading failed. {:?}",
+            err
+        ))),
+        Err(err) => Err(ErrorInternalServerError(format!(
+            "Communication with data cache failed. {:?}",
+            err
+        ))),
+    }
+}
+
+pub async fn evict_cache_entry(
+    req: HttpRequest,
+    params: web::Path<(String, usize)>,
+    cache: web::Data<Addr<data_cache::DataCache>>,
+    cfg: web::Data<dto::WebServiceConfig>,
+) -> Result<impl Responder, Error> {
+    check_admin_token(&req, &cfg)?;
+    let message = data_cache::EvictRequest {
+        simulation: params.0.clone(),
+        snapshot_id: params.1,
+    };
+    match cache.send(message).await {
+        Ok(true) => Ok(web::Json(dto::CacheEvictResponse { n_evicted: 1 })),
+        Ok(false) => Err(ErrorNotFound("Snapshot is neither cached nor loading.")),
+        Err(err) => Err(ErrorInternalServerError(format!(
+            "Communication with data cache failed. {:?}",
+            err
+        ))),
+    }
+}
+
+pub async fn clear_cache(
+    req: HttpRequest,
+    cache: web::Data<Addr<data_cache::DataCache>>,
+    cfg: web::Data<dto::WebServiceConfig>,
+) -> Result<impl Responder, Error> {
+    check_admin_token(&req, &cfg)?;
+    match cache.send(data_cache::ClearCacheRequest {}).await {
+        Ok(n_evicted) => Ok(web::Json(dto::CacheEvictResponse { n_evicted })),
+        Err(err) => Err(ErrorInternalServerError(format!(
+            "Communication with data cache failed. {:?}",
+            err
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_admin_token, clear_cache, data_cache, dto, evict_cache_entry, tokens_match};
+    use actix::Actor;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::TestRequest;
+    use actix_web::{web, Error, Responder};
+
+    fn admin_cfg() -> dto::WebServiceConfig {
+        dto::WebServiceConfig {
+            basedir: "/nonexistent".to_string(),
+            metadata_url: "http://localhost:0".to_string(),
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn status(result: Result<impl Responder, Error>) -> StatusCode {
+        let req = TestRequest::default().to_http_request();
+        match result {
+            Ok(responder) => responder.respond_to(&req).status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret-but-longer"));
+        assert!(!tokens_match("secret", ""));
+    }
+
+    #[test]
+    fn test_check_admin_token() {
+        let cfg = admin_cfg();
+        let bearer = |token: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_http_request()
+        };
+        assert!(check_admin_token(&bearer("secret"), &cfg).is_ok());
+        assert_eq!(
+            status(check_admin_token(&bearer("wrong"), &cfg).map(|_| "")),
+            StatusCode::UNAUTHORIZED
+        );
+        let missing = TestRequest::default().to_http_request();
+        assert_eq!(
+            status(check_admin_token(&missing, &cfg).map(|_| "")),
+            StatusCode::UNAUTHORIZED
+        );
+        let disabled = dto::WebServiceConfig {
+            admin_token: None,
+            ..admin_cfg()
+        };
+        assert_eq!(
+            status(check_admin_token(&bearer("secret"), &disabled).map(|_| "")),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_admin_handlers() {
+        let cfg = admin_cfg();
+        let cache = web::Data::new(data_cache::DataCache::new(&cfg, None).unwrap().start());
+        let cfg = web::Data::new(cfg);
+        let authorized = || {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_http_request()
+        };
+        let params = || web::Path::from(("TNG50-4".to_string(), 1));
+
+        let unauthorized = evict_cache_entry(
+            TestRequest::default().to_http_request(),
+            params(),
+            cache.clone(),
+            cfg.clone(),
+        )
+        .await;
+        assert_eq!(status(unauthorized), StatusCode::UNAUTHORIZED);
+
+        let not_cached =
+            evict_cache_entry(authorized(), params(), cache.clone(), cfg.clone()).await;
+        assert_eq!(status(not_cached), StatusCode::NOT_FOUND);
+
+        let cleared = clear_cache(authorized(), cache.clone(), cfg.clone()).await;
+        assert_eq!(status(cleared), StatusCode::OK);
+    }
+}
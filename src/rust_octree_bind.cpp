@@ -21,6 +21,19 @@ bool _box_intersect(Eigen::Vector3d min_box, Eigen::Vector3d max_box, RustVec3 m
     return (dx >= 0 && dy >= 0 && dz >= 0);
 }
 
+// Conservative box-frustum test: the box is outside if the corner furthest along the normal
+// of any plane is on the outer side of that plane.
+bool _box_in_frustum(Eigen::Vector3d min_box, Eigen::Vector3d max_box, const Frustum &frustum) {
+    for (const auto &plane : frustum.planes) {
+        auto px = plane.normal.x >= 0 ? max_box[0] : min_box[0];
+        auto py = plane.normal.y >= 0 ? max_box[1] : min_box[1];
+        auto pz = plane.normal.z >= 0 ? max_box[2] : min_box[2];
+        if (plane.normal.x * px + plane.normal.y * py + plane.normal.z * pz + plane.offset < 0)
+            return false;
+    }
+    return true;
+}
+
 
 std::shared_ptr<open3d::geometry::Octree> load_octree_from_file(rust::String file_name) {
     auto octree_ptr = std::make_shared<open3d::geometry::Octree>();
@@ -29,18 +42,26 @@ std::shared_ptr<open3d::geometry::Octree> load_octree_from_file(rust::String fil
     return octree_ptr;
 }
 
-rust::Vec<int64_t> get_intersecting_node(std::shared_ptr<open3d::geometry::Octree> octree, Viewbox viewbox) {
-    
-    rust::Vec<int64_t> particle_arr_ids;
-        
+// Leaves of all nodes for which `visible(min_box, max_box)` holds.
+template <class Visible>
+rust::Vec<NodeInfo> _intersecting_node_info(std::shared_ptr<open3d::geometry::Octree> octree, Visible visible) {
+
+    rust::Vec<NodeInfo> node_infos;
+
     auto traverse_lambda = [&](const std::shared_ptr<open3d::geometry::OctreeNode> &node, const std::shared_ptr<open3d::geometry::OctreeNodeInfo> &node_info) {
-        if (!_box_intersect((*node_info).origin_, (*node_info).origin_.array()+(*node_info).size_, viewbox.box_min, viewbox.box_max))
+        Eigen::Vector3d min_box = (*node_info).origin_;
+        Eigen::Vector3d max_box = (*node_info).origin_.array()+(*node_info).size_;
+        if (!visible(min_box, max_box))
             return true;
 
         if (IsType<open3d::geometry::OctreePointColorLeafNode>(&(*node))) {
 
             auto cast_node = dynamic_cast<open3d::geometry::OctreePointColorLeafNode*>(&(*node));
-            particle_arr_ids.push_back((int64_t)(*cast_node).indices_[0]);
+            node_infos.push_back(NodeInfo{
+                (int64_t)(*cast_node).indices_[0],
+                RustVec3{min_box[0], min_box[1], min_box[2]},
+                (*node_info).size_,
+            });
             return true;
         }
 
@@ -48,5 +69,17 @@ rust::Vec<int64_t> get_intersecting_node(std::shared_ptr<open3d::geometry::Octre
 
     };
     (*octree).Traverse(traverse_lambda);
-    return particle_arr_ids;
+    return node_infos;
+}
+
+rust::Vec<NodeInfo> get_intersecting_node_info(std::shared_ptr<open3d::geometry::Octree> octree, Viewbox viewbox) {
+    return _intersecting_node_info(octree, [&](Eigen::Vector3d min_box, Eigen::Vector3d max_box) {
+        return _box_intersect(min_box, max_box, viewbox.box_min, viewbox.box_max);
+    });
+}
+
+rust::Vec<NodeInfo> get_intersecting_node_info_frustum(std::shared_ptr<open3d::geometry::Octree> octree, Frustum frustum) {
+    return _intersecting_node_info(octree, [&](Eigen::Vector3d min_box, Eigen::Vector3d max_box) {
+        return _box_in_frustum(min_box, max_box, frustum);
+    });
 }
@@ -0,0 +1,123 @@
+use actix::*;
+use actix_cors::Cors;
+use actix_web::{middleware::Logger, web, App, HttpServer};
+
+use actix_web::rt::time::sleep;
+use reqwest::Client;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use super::{compression, data_cache, dto, requesthandler, session, warmup};
+
+async fn ping_metadata_server_coroutine(metadata_url: String, cache_server_url: String) {
+    loop {
+        let client = Client::new();
+        match client
+            .post(metadata_url.clone() + "/ping")
+            .header("User-Agent", &cache_server_url)
+            .send()
+            .await
+        {
+            Ok(_) => log::info!("Send ping to metadata server."),
+            Err(err) => log::warn!("Failed to send ping to metadata server {:?}", err),
+        }
+        sleep(Duration::from_secs(30)).await;
+    }
+}
+
+fn goodbye_metadata_server(metadata_url: String, hostname: String) {
+    match ureq::post(&(metadata_url.clone() + "/goodbye"))
+        .set("User-Agent", &hostname)
+        .call()
+    {
+        Ok(_) => log::info!("Send goodbye to metadata server."),
+        Err(err) => log::warn!("Failed to send goodbye to metadata server {:?}", err),
+    }
+}
+
+/// Load the config from `cfg.yml` and serve until the server is stopped.
+pub async fn run() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let cfg: dto::WebServiceConfig =
+        confy::load_path("cfg.yml").expect("Failed to load config from disk");
+
+    let memory_budget = cfg.memory_budget.as_ref().map(|budget| {
+        budget
+            .as_bytes()
+            .expect("Failed to determine memory budget")
+    });
+    match memory_budget {
+        Some(bytes) => log::info!(
+            "cache memory budget is {} MiB ({:?} eviction)",
+            bytes / (1024 * 1024),
+            cfg.eviction_policy
+        ),
+        None => log::info!("no cache memory budget configured, entries are never evicted"),
+    }
+
+    let cache = data_cache::DataCache::new(&cfg, memory_budget).map_err(|e| {
+        log::error!("{:?}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+    let handle = actix_rt::spawn(ping_metadata_server_coroutine(
+        cfg.metadata_url.clone(),
+        cfg.cache_server_url.clone(),
+    ));
+    let cache = cache.start();
+    let port = cfg.port;
+    let app_cfg = web::Data::new(cfg.clone());
+
+    let warmup_status = web::Data::new(RwLock::new(warmup::WarmupStatus::new(&cfg.preload)));
+    let sessions = web::Data::new(Mutex::new(session::SessionStore::new(&cfg.sessions)));
+    let warmup_handle = actix_rt::spawn(warmup::run_warmup(cache.clone(), warmup_status.clone()));
+
+    log::info!("starting HTTP server at http://localhost:8000");
+    let res = HttpServer::new(move || {
+        let cors = Cors::permissive();
+        App::new()
+            .app_data(web::Data::new(cache.clone()))
+            .app_data(app_cfg.clone())
+            .app_data(warmup_status.clone())
+            .app_data(sessions.clone())
+            .route("/rand", web::get().to(requesthandler::get_rand_init))
+            .service(
+                web::resource("/v1/get/splines/{simulation}/{snapshot_id}")
+                    .wrap_fn(compression::compress_response)
+                    .route(web::post().to(requesthandler::get_snapshot)),
+            )
+            .route(
+                "/v1/stream/{simulation}/{snapshot_id}",
+                web::get().to(requesthandler::stream_lod),
+            )
+            .service(
+                web::resource("/v1/get/init/{simulation}/{snapshot_id}")
+                    .wrap_fn(compression::compress_response)
+                    .route(web::get().to(requesthandler::get_init)),
+            )
+            .route(
+                "/v1/get/current_cache",
+                web::get().to(requesthandler::get_current_cache),
+            )
+            .route("/v1/get/status", web::get().to(requesthandler::get_status))
+            .route(
+                "/v1/cache/load/{simulation}/{snapshot_id}",
+                web::post().to(requesthandler::load_cache_entry),
+            )
+            .route(
+                "/v1/cache/{simulation}/{snapshot_id}",
+                web::delete().to(requesthandler::evict_cache_entry),
+            )
+            .route("/v1/cache", web::delete().to(requesthandler::clear_cache))
+            .wrap(Logger::default())
+            .wrap(cors)
+    })
+    .workers(2)
+    .bind(("127.0.0.1", port as u16))?
+    .run()
+    .await;
+    handle.abort();
+    warmup_handle.abort();
+    goodbye_metadata_server(cfg.metadata_url, cfg.cache_server_url);
+    res
+}
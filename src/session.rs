@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::data_cache::CacheRequest;
+use super::dto::SessionConfig;
+
+/// LOD progress of one client, the number of batches already sent per octree node of each
+/// snapshot.
+#[derive(Default)]
+pub struct Session {
+    pub level_of_detail: HashMap<CacheRequest, HashMap<i64, i64>>,
+    /// Particles of each snapshot the client got from the last transition into it, later lod
+    /// requests skip them.
+    pub transitioned: HashMap<CacheRequest, HashSet<i64>>,
+}
+
+impl Session {
+    pub fn size_in_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self
+                .level_of_detail
+                .iter()
+                .map(|(request, level_of_detail)| {
+                    size_of::<(CacheRequest, HashMap<i64, i64>)>()
+                        + request.simulation.len()
+                        + level_of_detail.capacity() * size_of::<(i64, i64)>()
+                })
+                .sum::<usize>()
+            + self
+                .transitioned
+                .iter()
+                .map(|(request, transitioned)| {
+                    size_of::<(CacheRequest, HashSet<i64>)>()
+                        + request.simulation.len()
+                        + transitioned.capacity() * size_of::<i64>()
+                })
+                .sum::<usize>()
+    }
+}
+
+struct SessionSlot {
+    session: Arc<Mutex<Session>>,
+    last_access: Instant,
+    size_in_bytes: usize,
+}
+
+/// Sessions issued by `/v1/get/init`. Sessions expire after `ttl` without requests, if the
+/// sessions together exceed the memory budget the least recently used ones are dropped.
+pub struct SessionStore {
+    sessions: HashMap<String, SessionSlot>,
+    ttl: Duration,
+    memory_budget: usize,
+    used_memory: usize,
+}
+
+impl SessionStore {
+    pub fn new(cfg: &SessionConfig) -> Self {
+        SessionStore {
+            sessions: HashMap::new(),
+            ttl: Duration::from_secs(cfg.ttl_secs),
+            memory_budget: cfg.memory_budget,
+            used_memory: 0,
+        }
+    }
+
+    pub fn create(&mut self) -> String {
+        self.expire();
+        let session_id = format!("{:032x}", rand::random::<u128>());
+        let session = Session::default();
+        let size_in_bytes = session.size_in_bytes();
+        self.sessions.insert(
+            session_id.clone(),
+            SessionSlot {
+                session: Arc::new(Mutex::new(session)),
+                last_access: Instant::now(),
+                size_in_bytes,
+            },
+        );
+        self.used_memory += size_in_bytes;
+        self.evict_to_fit(&session_id);
+        log::info!("session {} created, {} active", session_id, self.len());
+        session_id
+    }
+
+    /// The session with `session_id`, `None` if it is unknown or expired.
+    pub fn get(&mut self, session_id: &str) -> Option<Arc<Mutex<Session>>> {
+        self.expire();
+        let slot = self.sessions.get_mut(session_id)?;
+        slot.last_access = Instant::now();
+        Some(slot.session.clone())
+    }
+
+    /// Record the new size of a session after it was modified.
+    pub fn update_size(&mut self, session_id: &str, size_in_bytes: usize) {
+        if let Some(slot) = self.sessions.get_mut(session_id) {
+            self.used_memory = self.used_memory - slot.size_in_bytes + size_in_bytes;
+            slot.size_in_bytes = size_in_bytes;
+            self.evict_to_fit(session_id);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn remove(&mut self, session_id: &str) {
+        if let Some(slot) = self.sessions.remove(session_id) {
+            self.used_memory -= slot.size_in_bytes;
+        }
+    }
+
+    fn expire(&mut self) {
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, slot)| slot.last_access.elapsed() > self.ttl)
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+        for session_id in expired {
+            log::info!("session {} expired", session_id);
+            self.remove(&session_id);
+        }
+    }
+
+    /// Drop least recently used sessions until the budget holds. `keep` is never dropped.
+    fn evict_to_fit(&mut self, keep: &str) {
+        while self.used_memory > self.memory_budget {
+            let victim = self
+                .sessions
+                .iter()
+                .filter(|(session_id, _)| session_id.as_str() != keep)
+                .min_by_key(|(_, slot)| slot.last_access)
+                .map(|(session_id, _)| session_id.clone());
+            match victim {
+                Some(session_id) => {
+                    log::info!(
+                        "session {} dropped, session memory budget exceeded",
+                        session_id
+                    );
+                    self.remove(&session_id);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(ttl_secs: u64, memory_budget: usize) -> SessionStore {
+        SessionStore::new(&SessionConfig {
+            ttl_secs,
+            memory_budget,
+        })
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let mut sessions = store(0, usize::MAX);
+        let session_id = sessions.create();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(sessions.get(&session_id).is_none());
+        assert_eq!(sessions.len(), 0);
+    }
+
+    #[test]
+    fn test_least_recently_used_session_is_dropped() {
+        let empty = Session::default().size_in_bytes();
+        let mut sessions = store(600, 3 * empty);
+        let first = sessions.create();
+        let second = sessions.create();
+        let third = sessions.create();
+        assert_eq!(sessions.len(), 3);
+
+        // Growing the third session pushes out the least recently used one.
+        sessions.get(&first).unwrap();
+        sessions.update_size(&third, 2 * empty);
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.get(&second).is_none());
+        assert!(sessions.get(&first).is_some());
+        assert!(sessions.get(&third).is_some());
+    }
+}
@@ -0,0 +1,787 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use cxx::SharedPtr;
+use hdf5::{File, H5Type};
+use ndarray::{
+    concatenate, stack, Array, Array1, Array2, Axis, Dimension, Ix1, Ix2, Ix3, RemoveAxis,
+};
+use regex::Regex;
+
+use super::bind::ffi::{load_octree_from_file, Octree};
+use super::data_cache::{CacheEntry, CacheRequest};
+use super::dto::{LoadedField, LodField, NpyLoadMode, SourceConfig, WebServiceConfig};
+use super::fields;
+use super::npy::NpyArray;
+use super::s3::{ObjectInfo, S3Client};
+use super::utils;
+use super::watch::FileFingerprint;
+
+use anyhow::{anyhow, Context};
+
+/// What a source loads of a snapshot, see `WebServiceConfig::loaded_fields`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadedFields {
+    pub lod_fields: Vec<LodField>,
+    /// Names of the further particle fields, all that are found in the snapdir if `None`.
+    pub particle_fields: Option<Vec<String>>,
+    /// Whether the ParticleIDs are loaded, which transitions between snapshots need.
+    pub particle_ids: bool,
+}
+
+impl LoadedFields {
+    /// Config entry that loads the ParticleIDs.
+    pub const PARTICLE_IDS: &'static str = "ParticleIDs";
+
+    /// Everything, for simulations that are not listed in the config.
+    pub fn all() -> Self {
+        LoadedFields {
+            lod_fields: LodField::ALL.to_vec(),
+            particle_fields: None,
+            particle_ids: true,
+        }
+    }
+
+    pub fn from_config(entries: &[LoadedField]) -> Self {
+        let mut lod_fields = vec![];
+        let mut particle_fields = vec![];
+        let mut particle_ids = false;
+        for entry in entries {
+            match entry {
+                LoadedField::Lod(field) => lod_fields.push(*field),
+                LoadedField::Particle(name) if name == LoadedFields::PARTICLE_IDS => {
+                    particle_ids = true
+                }
+                LoadedField::Particle(name) => particle_fields.push(name.clone()),
+            }
+        }
+        LoadedFields {
+            lod_fields,
+            particle_fields: Some(particle_fields),
+            particle_ids,
+        }
+    }
+
+    pub fn contains_particle_field(&self, name: &str) -> bool {
+        match &self.particle_fields {
+            Some(names) => names.iter().any(|loaded| loaded == name),
+            None => true,
+        }
+    }
+}
+
+/// Where the data of a simulation comes from.
+///
+/// Implementations are called from the blocking thread pool and may do slow IO.
+pub trait SnapshotSource: Send + Sync {
+    /// Load the octree of a snapshot, the particle arrays and the particle fields of `fields`.
+    /// The arrays of other fields stay empty.
+    fn load(&self, request: &CacheRequest, fields: &LoadedFields) -> anyhow::Result<CacheEntry>;
+
+    /// Ids of all snapshots of a simulation, sorted ascending.
+    fn snapshot_ids(&self, simulation: &str) -> anyhow::Result<Vec<usize>>;
+
+    /// Side length of the simulation box, read from the group catalog or snapshot header.
+    fn box_size(&self, simulation: &str, snapshot_id: usize) -> anyhow::Result<usize>;
+}
+
+/// Preprocessed npy files in `basedir/simulation/snapdir_NNN/` on a local filesystem.
+pub struct NpyDirSource {
+    pub basedir: String,
+    pub npy_load_mode: NpyLoadMode,
+}
+
+impl NpyDirSource {
+    pub fn snapdir(&self, request: &CacheRequest) -> String {
+        self.basedir.clone()
+            + "/"
+            + &request.simulation
+            + "/"
+            + &format!("snapdir_{:03}", request.snapshot_id)
+            + "/"
+    }
+
+    /// Optional file in the snapdir with the ParticleIDs, needed to follow particles from one
+    /// snapshot to the next.
+    pub const PARTICLE_IDS: &'static str = "ParticleIDs.npy";
+
+    /// Files in the snapdir needed for `fields`.
+    pub fn file_names(fields: &[LodField]) -> Vec<&'static str> {
+        let mut file_names = DerivedProducts::file_names(fields);
+        if fields.contains(&LodField::Densities) {
+            file_names.push("Density.npy");
+        }
+        if fields.contains(&LodField::Coordinates) {
+            file_names.push("Coordinates.npy");
+        }
+        file_names
+    }
+}
+
+/// Products of the preprocessing that every source reads from npy files in the snapdir.
+pub struct DerivedProducts {
+    pub particle_list_of_leafs: NpyArray<i64, Ix1>,
+    pub particle_list_of_leafs_scan: NpyArray<i64, Ix1>,
+    pub splines: NpyArray<f64, Ix3>,
+    pub quantiles: NpyArray<f64, Ix1>,
+    pub voronoi_diameter_extended: NpyArray<f64, Ix1>,
+    pub octree: SharedPtr<Octree>,
+}
+
+impl DerivedProducts {
+    pub const FILE_NAMES: [&'static str; 6] = [
+        "particle_list_of_leafs_Density.npy",
+        "particle_list_of_leafs_Density_scan.npy",
+        "splines.npy",
+        "densities_quantiles.npy",
+        "voronoi_diameter_extended.npy",
+        "o3dOctree.json",
+    ];
+
+    /// The files of `FILE_NAMES` needed for `fields`.
+    pub fn file_names(fields: &[LodField]) -> Vec<&'static str> {
+        Self::FILE_NAMES
+            .iter()
+            .copied()
+            .filter(|file_name| match *file_name {
+                "splines.npy" => fields.contains(&LodField::Splines),
+                "voronoi_diameter_extended.npy" => fields.contains(&LodField::VoronoiDiameter),
+                _ => true,
+            })
+            .collect()
+    }
+
+    pub fn open(
+        basedir: &str,
+        npy_load_mode: NpyLoadMode,
+        fields: &[LodField],
+    ) -> anyhow::Result<Self> {
+        let basedir = basedir.to_string();
+        let particle_list_of_leafs = NpyArray::open(
+            basedir.clone() + "particle_list_of_leafs_Density.npy",
+            npy_load_mode,
+        )
+        .context("Failed to open particle_list_of_leafs")?;
+        let particle_list_of_leafs_scan = NpyArray::open(
+            basedir.clone() + "particle_list_of_leafs_Density_scan.npy",
+            npy_load_mode,
+        )
+        .context("Failed to open particle_list_of_leafs_scan")?;
+        let splines = if fields.contains(&LodField::Splines) {
+            NpyArray::open(basedir.clone() + "splines.npy", npy_load_mode)
+                .context("Failed to open splines")?
+        } else {
+            Array::zeros((0, 4, 3)).into()
+        };
+        let quantiles = NpyArray::open(basedir.clone() + "densities_quantiles.npy", npy_load_mode)
+            .context("Failed to open density_quantiles")?;
+        let voronoi_diameter_extended = if fields.contains(&LodField::VoronoiDiameter) {
+            NpyArray::open(
+                basedir.clone() + "voronoi_diameter_extended.npy",
+                npy_load_mode,
+            )
+            .context("Failed to open voronoi_diameter_extended")?
+        } else {
+            Array::zeros(0).into()
+        };
+
+        let octree = load_octree_from_file(basedir + "o3dOctree.json");
+
+        Ok(DerivedProducts {
+            particle_list_of_leafs,
+            particle_list_of_leafs_scan,
+            splines,
+            quantiles,
+            voronoi_diameter_extended,
+            octree,
+        })
+    }
+
+    /// Files of the particle fields in `snapdir` that `fields` asks for, besides the files
+    /// every snapdir has, and their precomputed quantiles. See `fields::discover`.
+    pub fn particle_field_files(
+        snapdir: &str,
+        fields: &LoadedFields,
+    ) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let mut known = NpyDirSource::file_names(&LodField::ALL);
+        known.push(NpyDirSource::PARTICLE_IDS);
+        let mut files = fields::discover(snapdir, &known)?;
+        files.retain(|(name, _)| fields.contains_particle_field(name));
+        if let Some(names) = &fields.particle_fields {
+            if let Some(missing) = names
+                .iter()
+                .find(|name| !files.iter().any(|(found, _)| found == *name))
+            {
+                return Err(anyhow!(
+                    "Particle field {} not found in {}",
+                    missing,
+                    snapdir
+                ));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Open the particle fields in `files`, see `particle_field_files`.
+    pub fn particle_fields(
+        &self,
+        files: &[(String, PathBuf)],
+        npy_load_mode: NpyLoadMode,
+    ) -> HashMap<String, fields::ParticleField> {
+        fields::open_all(
+            files,
+            self.particle_list_of_leafs.view().len(),
+            self.quantiles.view().len(),
+            npy_load_mode,
+        )
+    }
+
+    pub fn into_entry(
+        self,
+        coordinates: NpyArray<f64, Ix2>,
+        densities: NpyArray<f64, Ix2>,
+        snapdir: String,
+        fingerprint: FileFingerprint,
+        fields: &[LodField],
+    ) -> CacheEntry {
+        CacheEntry {
+            particle_list_of_leafs: self.particle_list_of_leafs,
+            particle_list_of_leafs_scan: self.particle_list_of_leafs_scan,
+            splines: self.splines,
+            densities,
+            quantiles: self.quantiles,
+            coordinates,
+            voronoi_diameter_extended: self.voronoi_diameter_extended,
+            octree: self.octree,
+            snapdir: Some(PathBuf::from(snapdir)),
+            fingerprint,
+            box_size: None,
+            fields: fields.to_vec(),
+            particle_fields: HashMap::new(),
+            particle_ids: Array::zeros(0).into(),
+        }
+    }
+}
+
+impl SnapshotSource for NpyDirSource {
+    fn load(&self, request: &CacheRequest, loaded: &LoadedFields) -> anyhow::Result<CacheEntry> {
+        let basedir = self.snapdir(request);
+        let npy_load_mode = self.npy_load_mode;
+        let fields = &loaded.lod_fields;
+
+        let field_files = DerivedProducts::particle_field_files(&basedir, loaded)?;
+        let particle_ids_path = PathBuf::from(basedir.clone() + NpyDirSource::PARTICLE_IDS);
+        // The ParticleIDs are optional, but adding them later has to reload the snapshot.
+        let fingerprint = FileFingerprint::of(
+            &NpyDirSource::file_names(fields)
+                .iter()
+                .map(|file_name| PathBuf::from(basedir.clone() + file_name))
+                .chain(field_files.iter().flat_map(|(_, path)| field_paths(path)))
+                .chain(loaded.particle_ids.then(|| particle_ids_path.clone()))
+                .collect::<Vec<PathBuf>>(),
+        );
+
+        let derived = DerivedProducts::open(&basedir, npy_load_mode, fields)?;
+        let particle_fields = derived.particle_fields(&field_files, npy_load_mode);
+        let densities = if fields.contains(&LodField::Densities) {
+            NpyArray::open(basedir.clone() + "Density.npy", npy_load_mode)
+                .context("Failed to open Density")?
+        } else {
+            Array::zeros((2, 0)).into()
+        };
+        let coordinates = if fields.contains(&LodField::Coordinates) {
+            NpyArray::open(basedir.clone() + "Coordinates.npy", npy_load_mode)
+                .context("Failed to open Coordinates")?
+        } else {
+            Array::zeros((0, 3)).into()
+        };
+        let particle_ids = if loaded.particle_ids && particle_ids_path.exists() {
+            NpyArray::open(particle_ids_path, npy_load_mode)
+                .context("Failed to open ParticleIDs")?
+        } else {
+            Array::zeros(0).into()
+        };
+
+        let mut entry = derived.into_entry(coordinates, densities, basedir, fingerprint, fields);
+        entry.particle_fields = particle_fields;
+        entry.particle_ids = particle_ids;
+        Ok(entry)
+    }
+
+    fn snapshot_ids(&self, simulation: &str) -> anyhow::Result<Vec<usize>> {
+        utils::available_snapshots(&self.basedir, simulation)
+    }
+
+    fn box_size(&self, simulation: &str, snapshot_id: usize) -> anyhow::Result<usize> {
+        let groupcat = self.basedir.clone()
+            + "/"
+            + simulation
+            + "/"
+            + &format!("groups_{:03}", snapshot_id)
+            + "/"
+            + &format!("fof_subhalo_tab_{:03}.0.hdf5", snapshot_id);
+        read_box_size(groupcat)
+    }
+}
+
+/// The file of a particle field and, if there is one, the file of its precomputed quantiles.
+fn field_paths(path: &Path) -> Vec<PathBuf> {
+    let quantiles_path = fields::quantiles_path(path);
+    if quantiles_path.exists() {
+        vec![path.to_path_buf(), quantiles_path]
+    } else {
+        vec![path.to_path_buf()]
+    }
+}
+
+/// Snapshots as written by the simulation, `snapdir_NNN/snap_NNN.*.hdf5`, with the products of
+/// the preprocessing (splines, octree, particle lists) as npy files in the same snapdir.
+///
+/// The preprocessing has to keep the particle order of the HDF5 chunks, the particle lists
+/// index into the concatenated `PartType0` datasets.
+///
+/// Only `Coordinates`, `Density` and `ParticleIDs` are read from `PartType0`. Other datasets
+/// such as `Temperature` are not offered as particle fields, they have to be exported to npy
+/// files in the snapdir like for `NpyDirSource`.
+pub struct Hdf5Source {
+    pub basedir: String,
+    pub npy_load_mode: NpyLoadMode,
+}
+
+impl Hdf5Source {
+    fn snapdir(&self, simulation: &str, snapshot_id: usize) -> String {
+        self.basedir.clone() + "/" + simulation + "/" + &format!("snapdir_{:03}", snapshot_id) + "/"
+    }
+
+    /// Chunk files of the snapshot after `request`, `None` for the last one.
+    fn next_chunk_files(&self, request: &CacheRequest) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        let snapshot_ids = self.snapshot_ids(&request.simulation)?;
+        snapshot_ids
+            .iter()
+            .find(|snapshot_id| **snapshot_id > request.snapshot_id)
+            .map(|next_snapshot_id| self.chunk_files(&request.simulation, *next_snapshot_id))
+            .transpose()
+    }
+
+    /// Density of this and, matched by particle id, of the next snapshot in `next_chunks`.
+    fn densities(
+        &self,
+        chunks: &[PathBuf],
+        next_chunks: Option<&[PathBuf]>,
+        ids: &Array1<u64>,
+    ) -> anyhow::Result<Array2<f64>> {
+        let density: Array1<f64> = read_part_type0(chunks, "Density")?;
+
+        // The second density row belongs to the next snapshot, the splines interpolate towards it.
+        let next_density = match next_chunks {
+            Some(next_chunks) => {
+                let next_ids: Array1<u64> = read_part_type0(next_chunks, "ParticleIDs")?;
+                let next_density: Array1<f64> = read_part_type0(next_chunks, "Density")?;
+                match_next_densities(ids, &density, &next_ids, &next_density)
+            }
+            None => density.clone(),
+        };
+        stack(Axis(0), &[density.view(), next_density.view()]).context("Failed to stack densities")
+    }
+
+    /// Chunk files `snap_NNN.<chunk>.hdf5` of a snapshot, ordered by chunk index.
+    pub fn chunk_files(
+        &self,
+        simulation: &str,
+        snapshot_id: usize,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let snapdir = self.snapdir(simulation, snapshot_id);
+        let regex = Regex::new(&format!(r"^snap_{:03}\.(\d+)\.hdf5$", snapshot_id))
+            .context("Failed to generate regex.")?;
+
+        let mut chunks = vec![];
+        for entry in
+            fs::read_dir(&snapdir).with_context(|| format!("Failed to list {}", snapdir))?
+        {
+            let path = entry?.path();
+            let file_name = path
+                .file_name()
+                .context("Failed to get filename.")?
+                .to_string_lossy()
+                .to_string();
+            if let Some(captures) = regex.captures(&file_name) {
+                let chunk = usize::from_str(&captures[1]).context("Failed to convert to usize")?;
+                chunks.push((chunk, path));
+            }
+        }
+        if chunks.is_empty() {
+            return Err(anyhow!("No snapshot chunks found in {}", snapdir));
+        }
+        chunks.sort_unstable_by_key(|(chunk, _)| *chunk);
+        Ok(chunks.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+/// Read a `PartType0` dataset from all chunks and concatenate along the particle axis.
+fn read_part_type0<A, D>(chunks: &[PathBuf], dataset: &str) -> anyhow::Result<Array<A, D>>
+where
+    A: H5Type + Clone,
+    D: Dimension + RemoveAxis,
+{
+    let mut parts: Vec<Array<A, D>> = vec![];
+    for chunk in chunks {
+        let file = File::open(chunk).with_context(|| format!("Failed to open {:?}", chunk))?;
+        // Chunks without gas cells do not contain a PartType0 group at all.
+        if !file.link_exists("PartType0") {
+            continue;
+        }
+        let part: Array<A, D> = file
+            .dataset(&format!("PartType0/{}", dataset))
+            .with_context(|| format!("Failed to access PartType0/{} in {:?}", dataset, chunk))?
+            .read()
+            .with_context(|| format!("Failed to read PartType0/{} in {:?}", dataset, chunk))?;
+        parts.push(part);
+    }
+    let views: Vec<_> = parts.iter().map(|part| part.view()).collect();
+    concatenate(Axis(0), &views).with_context(|| format!("Failed to concatenate {}", dataset))
+}
+
+/// Densities of the next snapshot in the order of the current one, matched by ParticleIDs.
+/// Cells that do not exist anymore keep their current density.
+///
+/// The ids of the next snapshot are looked up in their sort order, which only takes one index
+/// per particle of temporary memory.
+pub fn match_next_densities(
+    ids: &Array1<u64>,
+    densities: &Array1<f64>,
+    next_ids: &Array1<u64>,
+    next_densities: &Array1<f64>,
+) -> Array1<f64> {
+    let mut order: Vec<usize> = (0..next_ids.len()).collect();
+    order.sort_unstable_by_key(|idx| next_ids[*idx]);
+    ids.iter()
+        .zip(densities.iter())
+        .map(
+            |(id, density)| match order.binary_search_by_key(id, |idx| next_ids[*idx]) {
+                Ok(position) => next_densities[order[position]],
+                Err(_) => *density,
+            },
+        )
+        .collect()
+}
+
+impl SnapshotSource for Hdf5Source {
+    fn load(&self, request: &CacheRequest, loaded: &LoadedFields) -> anyhow::Result<CacheEntry> {
+        let basedir = self.snapdir(&request.simulation, request.snapshot_id);
+        let chunks = self.chunk_files(&request.simulation, request.snapshot_id)?;
+        let fields = &loaded.lod_fields;
+
+        // The second density row is read from the next snapshot, rewriting it changes the entry.
+        let next_chunks = if fields.contains(&LodField::Densities) {
+            self.next_chunk_files(request)?
+        } else {
+            None
+        };
+        let field_files = DerivedProducts::particle_field_files(&basedir, loaded)?;
+        let fingerprint = FileFingerprint::of(
+            &DerivedProducts::file_names(fields)
+                .iter()
+                .map(|file_name| PathBuf::from(basedir.clone() + file_name))
+                .chain(chunks.iter().cloned())
+                .chain(next_chunks.iter().flatten().cloned())
+                .chain(field_files.iter().flat_map(|(_, path)| field_paths(path)))
+                .collect::<Vec<PathBuf>>(),
+        );
+
+        let derived = DerivedProducts::open(&basedir, self.npy_load_mode, fields)?;
+        let particle_fields = derived.particle_fields(&field_files, self.npy_load_mode);
+        let coordinates: Array2<f64> = if fields.contains(&LodField::Coordinates) {
+            read_part_type0(&chunks, "Coordinates")?
+        } else {
+            Array::zeros((0, 3))
+        };
+        // The densities of the next snapshot are matched by ParticleIDs.
+        let particle_ids: Array1<u64> =
+            if loaded.particle_ids || fields.contains(&LodField::Densities) {
+                read_part_type0(&chunks, "ParticleIDs")?
+            } else {
+                Array::zeros(0)
+            };
+        let densities = if fields.contains(&LodField::Densities) {
+            self.densities(&chunks, next_chunks.as_deref(), &particle_ids)?
+        } else {
+            Array::zeros((2, 0))
+        };
+
+        let mut entry = derived.into_entry(
+            coordinates.into(),
+            densities.into(),
+            basedir,
+            fingerprint,
+            fields,
+        );
+        entry.particle_fields = particle_fields;
+        if loaded.particle_ids {
+            entry.particle_ids = particle_ids.into();
+        }
+        Ok(entry)
+    }
+
+    fn snapshot_ids(&self, simulation: &str) -> anyhow::Result<Vec<usize>> {
+        utils::available_snapshots(&self.basedir, simulation)
+    }
+
+    fn box_size(&self, simulation: &str, snapshot_id: usize) -> anyhow::Result<usize> {
+        let chunks = self.chunk_files(simulation, snapshot_id)?;
+        let file = File::open(&chunks[0]).context("Failed to open snapshot chunk")?;
+        let box_size = file
+            .group("Header")
+            .context("Failed to access header group")?
+            .attr("BoxSize")
+            .context("Failed to access box size attribute.")?
+            .read_scalar::<f64>()
+            .context("Failed to read scalar.")?;
+        Ok(box_size as usize)
+    }
+}
+
+/// The npy layout stored in an S3 compatible object store under
+/// `prefix/simulation/snapdir_NNN/`.
+///
+/// Files are downloaded into `spill_dir` on first use and loaded from there, so the spill dir
+/// doubles as a local cache that survives restarts.
+pub struct S3Source {
+    pub client: S3Client,
+    pub prefix: String,
+    pub range_size: u64,
+    pub spill: NpyDirSource,
+}
+
+impl S3Source {
+    fn key(&self, path: &str) -> String {
+        let prefix = self.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            path.to_string()
+        } else {
+            prefix.to_string() + "/" + path
+        }
+    }
+
+    /// Download `simulation/path` into the spill dir unless it is already there.
+    fn fetch(&self, simulation: &str, path: &str) -> anyhow::Result<PathBuf> {
+        let relative = simulation.to_string() + "/" + path;
+        let local = PathBuf::from(self.spill.basedir.clone() + "/" + &relative);
+        self.client
+            .download(&self.key(&relative), &local, self.range_size)?;
+        Ok(local)
+    }
+
+    /// Like `fetch`, with size and ETag taken from a listing instead of a HEAD request.
+    fn fetch_listed(&self, simulation: &str, path: &str, info: &ObjectInfo) -> anyhow::Result<()> {
+        let relative = simulation.to_string() + "/" + path;
+        let local = PathBuf::from(self.spill.basedir.clone() + "/" + &relative);
+        self.client
+            .download_object(&self.key(&relative), info, &local, self.range_size)
+    }
+}
+
+impl SnapshotSource for S3Source {
+    fn load(&self, request: &CacheRequest, loaded: &LoadedFields) -> anyhow::Result<CacheEntry> {
+        let fields = &loaded.lod_fields;
+        let snapdir = format!("snapdir_{:03}", request.snapshot_id);
+        // One listing provides size and ETag of all files, there is no HEAD request per file.
+        let snapdir_key = self.key(&(request.simulation.clone() + "/" + &snapdir + "/"));
+        let objects: HashMap<String, ObjectInfo> = self
+            .client
+            .list_objects(&snapdir_key)?
+            .into_iter()
+            .filter_map(|(key, info)| Some((key.strip_prefix(&snapdir_key)?.to_string(), info)))
+            .collect();
+
+        for file_name in NpyDirSource::file_names(fields) {
+            let info = objects
+                .get(file_name)
+                .ok_or_else(|| anyhow!("Object {}{} does not exist", snapdir_key, file_name))?;
+            self.fetch_listed(
+                &request.simulation,
+                &(snapdir.clone() + "/" + file_name),
+                info,
+            )?;
+        }
+        // Further particle fields with their quantiles and the optional ParticleIDs are
+        // discovered in the spill dir, fetch them if they are loaded.
+        let known = NpyDirSource::file_names(&LodField::ALL);
+        for (file_name, info) in &objects {
+            let field = match file_name.strip_suffix("_quantiles.npy") {
+                Some(name) if !known.contains(&file_name.as_str()) => Some(name.to_string()),
+                _ => fields::field_name(file_name, &known),
+            };
+            if (file_name == NpyDirSource::PARTICLE_IDS && loaded.particle_ids)
+                || field.is_some_and(|name| loaded.contains_particle_field(&name))
+            {
+                self.fetch_listed(
+                    &request.simulation,
+                    &(snapdir.clone() + "/" + file_name),
+                    info,
+                )?;
+            }
+        }
+        self.spill.load(request, loaded)
+    }
+
+    fn snapshot_ids(&self, simulation: &str) -> anyhow::Result<Vec<usize>> {
+        let regex = Regex::new(r"snapdir_(\d+)/$").context("Failed to generate regex.")?;
+        let mut snapshot_ids = self
+            .client
+            .list_prefixes(&(self.key(simulation) + "/"))?
+            .iter()
+            .filter_map(|prefix| regex.captures(prefix))
+            .map(|captures| usize::from_str(&captures[1]).context("Failed to convert to usize"))
+            .collect::<anyhow::Result<Vec<usize>>>()?;
+        snapshot_ids.sort_unstable();
+        Ok(snapshot_ids)
+    }
+
+    fn box_size(&self, simulation: &str, snapshot_id: usize) -> anyhow::Result<usize> {
+        let groupcat = self.fetch(
+            simulation,
+            &format!(
+                "groups_{:03}/fof_subhalo_tab_{:03}.0.hdf5",
+                snapshot_id, snapshot_id
+            ),
+        )?;
+        read_box_size(groupcat.to_string_lossy().to_string())
+    }
+}
+
+pub fn from_config(
+    cfg: &WebServiceConfig,
+    source: &SourceConfig,
+) -> anyhow::Result<Arc<dyn SnapshotSource>> {
+    Ok(match source {
+        SourceConfig::NpyDir {
+            basedir,
+            npy_load_mode,
+        } => Arc::new(NpyDirSource {
+            basedir: basedir.clone().unwrap_or_else(|| cfg.basedir.clone()),
+            npy_load_mode: npy_load_mode.unwrap_or(cfg.npy_load_mode),
+        }),
+        SourceConfig::Hdf5 {
+            basedir,
+            npy_load_mode,
+        } => Arc::new(Hdf5Source {
+            basedir: basedir.clone().unwrap_or_else(|| cfg.basedir.clone()),
+            npy_load_mode: npy_load_mode.unwrap_or(cfg.npy_load_mode),
+        }),
+        SourceConfig::S3 {
+            prefix,
+            npy_load_mode,
+        } => {
+            let s3 = cfg
+                .s3
+                .as_ref()
+                .context("Sources of type s3 require the s3 section in the config")?;
+            Arc::new(S3Source {
+                client: S3Client::new(s3),
+                prefix: prefix.clone().unwrap_or_else(|| s3.prefix.clone()),
+                range_size: s3.range_size,
+                spill: NpyDirSource {
+                    basedir: s3.spill_dir.clone(),
+                    npy_load_mode: npy_load_mode.unwrap_or(cfg.npy_load_mode),
+                },
+            })
+        }
+    })
+}
+
+pub fn read_box_size(groupcat: String) -> anyhow::Result<usize> {
+    let file = File::open(groupcat).context("Failed to open groupcat")?;
+    let header = file
+        .group("Header")
+        .context("Failed to access header group")?;
+    let attribute = header
+        .attr("BoxSize")
+        .context("Failed to access box size attribute.")?;
+    attribute
+        .read_scalar::<usize>()
+        .context("Failed te read scalar.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_match_next_densities() {
+        let ids = array![10, 11, 12, 13];
+        let densities = array![1.0, 2.0, 3.0, 4.0];
+        // Cell 12 is gone in the next snapshot, the others are shuffled.
+        let next_ids = array![13, 10, 11, 14];
+        let next_densities = array![40.0, 10.0, 20.0, 50.0];
+
+        let matched = match_next_densities(&ids, &densities, &next_ids, &next_densities);
+        assert_eq!(matched, array![10.0, 20.0, 3.0, 40.0]);
+    }
+
+    #[test]
+    fn test_loaded_fields() {
+        let entries: Vec<LoadedField> =
+            serde_json::from_str(r#"["coordinates", "Temperature", "densities", "ParticleIDs"]"#)
+                .unwrap();
+        let loaded = LoadedFields::from_config(&entries);
+        assert_eq!(
+            loaded.lod_fields,
+            vec![LodField::Coordinates, LodField::Densities]
+        );
+        assert!(loaded.contains_particle_field("Temperature"));
+        assert!(!loaded.contains_particle_field("Velocities"));
+        assert!(!loaded.contains_particle_field(LoadedFields::PARTICLE_IDS));
+        assert!(loaded.particle_ids);
+        let entries: Vec<LoadedField> = serde_json::from_str(r#"["coordinates"]"#).unwrap();
+        assert!(!LoadedFields::from_config(&entries).particle_ids);
+        assert!(LoadedFields::all().contains_particle_field("Velocities"));
+    }
+
+    #[test]
+    fn test_particle_field_files() {
+        let snapdir = std::env::temp_dir().join("cache_server_particle_field_files_test");
+        fs::create_dir_all(&snapdir).unwrap();
+        for file_name in [
+            "splines.npy",
+            "Temperature.npy",
+            "Temperature_quantiles.npy",
+            "Velocities.npy",
+            NpyDirSource::PARTICLE_IDS,
+        ] {
+            fs::write(snapdir.join(file_name), b"").unwrap();
+        }
+        let snapdir_str = snapdir.to_string_lossy().to_string() + "/";
+
+        let all =
+            DerivedProducts::particle_field_files(&snapdir_str, &LoadedFields::all()).unwrap();
+        let names: Vec<&str> = all.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["Temperature", "Velocities"]);
+        assert_eq!(
+            field_paths(&all[0].1),
+            vec![
+                snapdir.join("Temperature.npy"),
+                snapdir.join("Temperature_quantiles.npy")
+            ]
+        );
+
+        let loaded = LoadedFields {
+            lod_fields: vec![],
+            particle_fields: Some(vec!["Velocities".to_string()]),
+            particle_ids: false,
+        };
+        let some = DerivedProducts::particle_field_files(&snapdir_str, &loaded).unwrap();
+        assert_eq!(some, vec![all[1].clone()]);
+        let missing = LoadedFields {
+            lod_fields: vec![],
+            particle_fields: Some(vec!["Metallicity".to_string()]),
+            particle_ids: false,
+        };
+        assert!(DerivedProducts::particle_field_files(&snapdir_str, &missing).is_err());
+
+        fs::remove_dir_all(snapdir).unwrap();
+    }
+}
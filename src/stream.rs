@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::rt::task::spawn_blocking;
+use actix_web_actors::ws;
+use anyhow::anyhow;
+
+use super::data_cache::{CacheEntry, CacheRequest, DataCache};
+use super::dto::{
+    LodParameters, ParticleFloat, Precision, StreamBatch, StreamEvent, StreamMessage, StreamUpdate,
+};
+use super::lod;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Batches a client may have unacknowledged before the stream waits for an ack.
+const MAX_UNACKED_BATCHES: usize = 2;
+
+/// Sequence numbers of the batches that were sent but not acknowledged yet.
+#[derive(Default)]
+struct FlowControl {
+    last_sequence: u64,
+    unacked: VecDeque<u64>,
+}
+
+impl FlowControl {
+    fn can_send(&self) -> bool {
+        self.unacked.len() < MAX_UNACKED_BATCHES
+    }
+
+    /// Sequence number for the next batch, numbers of dropped batches are not reused.
+    fn next_sequence(&mut self) -> u64 {
+        self.last_sequence += 1;
+        self.last_sequence
+    }
+
+    fn sent(&mut self, sequence: u64) {
+        self.unacked.push_back(sequence);
+    }
+
+    /// The client processed all batches up to `sequence`.
+    fn ack(&mut self, sequence: u64) {
+        while self.unacked.front().is_some_and(|sent| *sent <= sequence) {
+            self.unacked.pop_front();
+        }
+    }
+}
+
+/// WebSocket connection that streams LOD batches of one snapshot.
+///
+/// The client sends a `StreamUpdate` whenever the camera moves, it takes the same parameters
+/// as a request to `/v1/get/splines/{simulation}/{snapshot_id}`. The server answers with
+/// successive batches for the current view until it is fully refined, then sends `done`. A
+/// batch that was computed for an outdated camera is dropped instead of being sent. The LOD
+/// progress lives in the connection, independent of sessions.
+///
+/// Clients acknowledge batches with `{"ack": sequence}`. At most `MAX_UNACKED_BATCHES` are
+/// sent ahead, so a slow client is not flooded with batches it can not keep up with.
+pub struct LodStream {
+    cache: Addr<DataCache>,
+    request: CacheRequest,
+    level_of_detail: HashMap<i64, i64>,
+    /// The view that is refined, `None` once it is fully refined.
+    view: Option<StreamUpdate>,
+    /// Increased with every camera update, batches of older generations are stale.
+    generation: u64,
+    in_flight: bool,
+    flow_control: FlowControl,
+    heartbeat: Instant,
+}
+
+/// Compute the next batch for `parameters` and serialize it, with its number of particles and
+/// whether the view is refined afterwards.
+fn stream_batch<F: ParticleFloat>(
+    cache_entry: &CacheEntry,
+    parameters: &LodParameters,
+    level_of_detail: &mut HashMap<i64, i64>,
+    snapshot_id: usize,
+    sequence: u64,
+) -> anyhow::Result<(usize, bool, String)> {
+    let options = lod::LodOptions::new(parameters, cache_entry)?;
+    let color_field = lod::color_field(parameters, cache_entry)?;
+    let result = lod::calc_lod::<F>(
+        cache_entry.particle_list_of_leafs.view(),
+        cache_entry.particle_list_of_leafs_scan.view(),
+        cache_entry.splines.view(),
+        cache_entry.densities.view(),
+        cache_entry.coordinates.view(),
+        cache_entry.voronoi_diameter_extended.view(),
+        color_field,
+        cache_entry.octree.clone(),
+        parameters.batch_size_lod,
+        &parameters.camera_information,
+        level_of_detail,
+        // Streams are independent of sessions and their transitions.
+        &HashSet::new(),
+        snapshot_id,
+        &options,
+    )?;
+    let (n_particles, refined) = (result.n_particles, result.refined);
+    let text = serde_json::to_string(&StreamBatch { sequence, result })?;
+    Ok((n_particles, refined, text))
+}
+
+impl LodStream {
+    pub fn new(cache: Addr<DataCache>, request: CacheRequest) -> Self {
+        LodStream {
+            cache,
+            request,
+            level_of_detail: HashMap::new(),
+            view: None,
+            generation: 0,
+            in_flight: false,
+            flow_control: FlowControl::default(),
+            heartbeat: Instant::now(),
+        }
+    }
+
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, event: &StreamEvent) {
+        match serde_json::to_string(event) {
+            Ok(text) => ctx.text(text),
+            Err(err) => log::warn!("Failed to serialize stream event {:?}", err),
+        }
+    }
+
+    /// Compute the next batch for the current view unless one is already running or the client
+    /// has to acknowledge batches first.
+    fn step(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.in_flight || !self.flow_control.can_send() {
+            // Picked up again once the running batch returned or an ack arrived.
+            return;
+        }
+        let view = match &self.view {
+            Some(view) => view.clone(),
+            None => return,
+        };
+        self.in_flight = true;
+
+        let generation = self.generation;
+        let sequence = self.flow_control.next_sequence();
+        let cache = self.cache.clone();
+        let request = self.request.clone();
+        let snapshot_id = request.snapshot_id;
+        // The progress only advances when the batch is actually sent.
+        let mut level_of_detail = self.level_of_detail.clone();
+        let batch = async move {
+            let cache_entry = cache
+                .send(request)
+                .await
+                .map_err(|err| anyhow!("Communication with data cache failed: {:?}.", err))?
+                .map_err(|err| anyhow!("Data loading failed: {:?}.", err))?;
+            spawn_blocking(
+                move || -> anyhow::Result<(usize, bool, String, HashMap<i64, i64>)> {
+                    let parameters = &view.parameters;
+                    let (n_particles, refined, text) = match parameters.precision {
+                        Precision::F32 => stream_batch::<f32>(
+                            &cache_entry,
+                            parameters,
+                            &mut level_of_detail,
+                            snapshot_id,
+                            sequence,
+                        ),
+                        Precision::F64 => stream_batch::<f64>(
+                            &cache_entry,
+                            parameters,
+                            &mut level_of_detail,
+                            snapshot_id,
+                            sequence,
+                        ),
+                    }?;
+                    Ok((n_particles, refined, text, level_of_detail))
+                },
+            )
+            .await
+            .map_err(|err| anyhow!("Lod calculation panicked: {:?}.", err))?
+        };
+
+        ctx.spawn(batch.into_actor(self).map(move |result, act, ctx| {
+            act.in_flight = false;
+            if generation != act.generation {
+                // The camera moved while the batch was computed.
+                act.step(ctx);
+                return;
+            }
+            match result {
+                Ok((n_particles, refined, text, level_of_detail)) => {
+                    act.level_of_detail = level_of_detail;
+                    // Batches can be empty while other nodes still have particles left, e.g.
+                    // if a density range matched nothing in the nodes that were scanned.
+                    if n_particles > 0 {
+                        act.flow_control.sent(sequence);
+                        ctx.text(text);
+                    }
+                    if refined {
+                        act.view = None;
+                        act.send(
+                            ctx,
+                            &StreamEvent::Done {
+                                snapshot_id: act.request.snapshot_id,
+                            },
+                        );
+                    } else {
+                        act.step(ctx);
+                    }
+                }
+                Err(err) => {
+                    act.view = None;
+                    act.send(
+                        ctx,
+                        &StreamEvent::Error {
+                            message: format!("{:?}", err),
+                        },
+                    );
+                }
+            }
+        }));
+    }
+}
+
+impl Actor for LodStream {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
+                log::info!("lod stream client timed out");
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LodStream {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(bytes)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&bytes);
+            }
+            Ok(ws::Message::Pong(_)) => self.heartbeat = Instant::now(),
+            Ok(ws::Message::Text(text)) => {
+                self.heartbeat = Instant::now();
+                match serde_json::from_str::<StreamMessage>(&text) {
+                    Ok(StreamMessage::Ack { ack }) => {
+                        self.flow_control.ack(ack);
+                        self.step(ctx);
+                    }
+                    Ok(StreamMessage::Update(update)) => {
+                        self.generation += 1;
+                        if let Err(err) = update.parameters.check() {
+                            // Batches of the previous view are stale as well.
+                            self.view = None;
+                            self.send(
+                                ctx,
+                                &StreamEvent::Error {
+                                    message: format!("Invalid camera update: {}", err),
+                                },
+                            );
+                            return;
+                        }
+                        self.view = Some(update);
+                        self.step(ctx);
+                    }
+                    Err(_) => {
+                        // Acks are short, report why the message is no camera update.
+                        let message = match serde_json::from_str::<StreamUpdate>(&text) {
+                            Err(err) => format!("Invalid camera update: {}", err),
+                            Ok(_) => "Invalid stream message.".to_string(),
+                        };
+                        self.send(ctx, &StreamEvent::Error { message })
+                    }
+                }
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(err) => {
+                log::warn!("lod stream protocol error {:?}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dto::LodResult;
+    use super::*;
+
+    #[test]
+    fn test_flow_control() {
+        let mut flow_control = FlowControl::default();
+        let first = flow_control.next_sequence();
+        flow_control.sent(first);
+        // The second batch was computed for an outdated camera and dropped.
+        flow_control.next_sequence();
+        let third = flow_control.next_sequence();
+        flow_control.sent(third);
+        assert_eq!(third, 3);
+        assert!(!flow_control.can_send());
+
+        flow_control.ack(first);
+        assert!(flow_control.can_send());
+        let fourth = flow_control.next_sequence();
+        flow_control.sent(fourth);
+        assert!(!flow_control.can_send());
+        // Acks arriving out of order or twice do not free more than was sent.
+        flow_control.ack(4);
+        flow_control.ack(2);
+        assert!(flow_control.unacked.is_empty());
+    }
+
+    #[test]
+    fn test_stream_messages() {
+        let ack = serde_json::from_str::<StreamMessage>(r#"{"ack": 7}"#).unwrap();
+        assert!(matches!(ack, StreamMessage::Ack { ack: 7 }));
+
+        let update = serde_json::from_str::<StreamMessage>(
+            r#"{
+                "batch_size_lod": 100,
+                "camera_information": {"x": 1.0, "y": 2.0, "z": 3.0, "size": 4.0},
+                "precision": "f32",
+                "relative_to_camera": true,
+                "max_particles": 500,
+                "min_quantile": 0.5,
+                "color_field": "Temperature",
+                "time": 0.25
+            }"#,
+        )
+        .unwrap();
+        let parameters = match update {
+            StreamMessage::Update(update) => update.parameters,
+            StreamMessage::Ack { .. } => panic!("Camera update parsed as ack."),
+        };
+        assert_eq!(parameters.batch_size_lod, 100);
+        assert_eq!(parameters.precision, Precision::F32);
+        assert!(parameters.relative_to_camera);
+        assert_eq!(parameters.max_particles, Some(500));
+        assert_eq!(parameters.density_range.min_quantile, Some(0.5));
+        assert_eq!(parameters.color_field.as_deref(), Some("Temperature"));
+        assert_eq!(parameters.time, Some(0.25));
+
+        let batch = StreamBatch::<f32> {
+            sequence: 3,
+            result: LodResult {
+                splines_a: vec![1.0; 3],
+                splines_b: vec![2.0; 3],
+                splines_c: vec![3.0; 3],
+                splines_d: vec![4.0; 3],
+                relevant_densities_flat: vec![5.0, 6.0],
+                relevant_coordinates: vec![vec![7.0, 8.0, 9.0]],
+                relevant_voronoi_diameter_extended: vec![10.0],
+                client_level_of_detail: None,
+                min_d: 5.0,
+                max_d: 6.0,
+                n_particles: 1,
+                fields: vec![],
+                snapshot_id: 99,
+                node_indices: None,
+                origin: None,
+                color: None,
+                interpolated: None,
+                refined: true,
+            },
+        };
+        let event: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&batch).unwrap()).unwrap();
+        assert_eq!(event["type"], "batch");
+        assert_eq!(event["sequence"], 3);
+        assert_eq!(event["snapnum"], 99);
+        assert!(event.get("refined").is_none());
+    }
+}
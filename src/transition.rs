@@ -0,0 +1,286 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+
+use ndarray::{s, ArrayView1};
+
+use super::data_cache::CacheEntry;
+use super::dto::{CameraInfo, LodField, LodResult, ParticleFloat};
+use super::fields::ParticleField;
+use super::lod::{self, LodOptions, ParticleArrays};
+
+use anyhow::anyhow;
+
+/// Particles a client with `level_of_detail` received of a snapshot, nodes closest to the
+/// camera first. `distances` holds the distance of the nodes in view, the others come last.
+///
+/// Every node sent the first `level * lod_batch` of its particles. Density ranges of the
+/// requests are not taken into account.
+pub fn received_particles(
+    particle_list_of_leafs: ArrayView1<i64>,
+    particle_list_of_leafs_scan: ArrayView1<i64>,
+    level_of_detail: &HashMap<i64, i64>,
+    distances: &HashMap<i64, f64>,
+    lod_batch: i64,
+) -> Vec<i64> {
+    let mut nodes: Vec<(i64, i64)> = level_of_detail
+        .iter()
+        .map(|(node, lod)| (*node, *lod))
+        .collect();
+    let distance = |node: i64| distances.get(&node).copied().unwrap_or(f64::INFINITY);
+    nodes.sort_unstable_by(|(a, _), (b, _)| distance(*a).total_cmp(&distance(*b)).then(a.cmp(b)));
+
+    let mut particles = vec![];
+    for (node, lod) in nodes {
+        let node = node as usize;
+        // Clients without a session may send back anything.
+        if node >= particle_list_of_leafs_scan.len() {
+            continue;
+        }
+        let start = particle_list_of_leafs_scan[node] as usize;
+        let stop = if node != particle_list_of_leafs_scan.len() - 1 {
+            particle_list_of_leafs_scan[node + 1] as usize
+        } else {
+            particle_list_of_leafs.len()
+        };
+        let len = min(lod * lod_batch, (stop - start) as i64).max(0) as usize;
+        particles.extend(particle_list_of_leafs.slice(s![start..start + len]));
+    }
+    particles
+}
+
+/// Indices into the particle arrays of the next snapshot of the `particles` of this one,
+/// matched by ParticleIDs. The order is kept, particles that do not exist in the next
+/// snapshot anymore are left out.
+pub fn match_particles(
+    particles: &[i64],
+    ids: ArrayView1<u64>,
+    next_ids: ArrayView1<u64>,
+) -> Vec<i64> {
+    // Only the received particles are hashed, the next snapshot is scanned once.
+    let positions: HashMap<u64, usize> = particles
+        .iter()
+        .enumerate()
+        .map(|(position, particle)| (ids[*particle as usize], position))
+        .collect();
+    let mut matched: Vec<Option<i64>> = vec![None; particles.len()];
+    for (idx, id) in next_ids.iter().enumerate() {
+        if let Some(position) = positions.get(id) {
+            matched[*position] = Some(idx as i64);
+        }
+    }
+    matched.into_iter().flatten().collect()
+}
+
+/// Shift that moves `position` into the periodic image closest to `camera`.
+fn nearest_image(position: [f64; 3], camera: [f64; 3], box_size: Option<f64>) -> [f64; 3] {
+    match box_size {
+        Some(box_size) if box_size > 0.0 => {
+            std::array::from_fn(|k| -((position[k] - camera[k]) / box_size).round() * box_size)
+        }
+        _ => [0.0; 3],
+    }
+}
+
+/// The particles a client has of the `previous` snapshot as they are in `entry`, so that it
+/// can replace them before it refines the next snapshot.
+///
+/// The level of detail of `entry` does not advance. The particles that are sent replace
+/// `transitioned`, `calc_lod` skips them when the nodes of `entry` are refined.
+pub fn calc_transition<F: ParticleFloat>(
+    previous: &CacheEntry,
+    previous_level_of_detail: &HashMap<i64, i64>,
+    entry: &CacheEntry,
+    color_field: Option<(&str, &ParticleField)>,
+    lod_batch: i64,
+    camera_information: &CameraInfo,
+    transitioned: &mut HashSet<i64>,
+    snapshot_id: usize,
+    options: &LodOptions,
+) -> anyhow::Result<LodResult<F>> {
+    if previous.particle_ids.view().is_empty() || entry.particle_ids.view().is_empty() {
+        return Err(anyhow!(
+            "Transitions require the ParticleIDs of both snapshots."
+        ));
+    }
+    if options.density_range.is_some() && entry.densities.view().is_empty() {
+        return Err(anyhow!(
+            "Filtering by density requires the densities to be loaded."
+        ));
+    }
+    if options.time.is_some()
+        && (entry.splines.view().is_empty() || entry.densities.view().is_empty())
+    {
+        return Err(anyhow!(
+            "Interpolation requires the splines and densities to be loaded."
+        ));
+    }
+
+    let camera_position = [
+        camera_information.x,
+        camera_information.y,
+        camera_information.z,
+    ];
+    // The nearest particles are kept if `max_particles` does not suffice for all.
+    let distances: HashMap<i64, f64> = lod::visible_nodes(
+        previous.octree.clone(),
+        camera_information,
+        options.box_size,
+    )
+    .iter()
+    .map(|(node, _)| (node.index, node.distance_to(camera_position)))
+    .collect();
+    let received = received_particles(
+        previous.particle_list_of_leafs.view(),
+        previous.particle_list_of_leafs_scan.view(),
+        previous_level_of_detail,
+        &distances,
+        lod_batch,
+    );
+    let mut relevant_ids = match_particles(
+        &received,
+        previous.particle_ids.view(),
+        entry.particle_ids.view(),
+    );
+    if let Some((min_density, max_density)) = options.density_range {
+        let densities = entry.densities.view();
+        relevant_ids.retain(|id| {
+            let density = densities[[0, *id as usize]];
+            min_density <= density && density <= max_density
+        });
+    }
+    if let Some(max_particles) = options.max_particles {
+        relevant_ids.truncate(max_particles);
+    }
+    let n_particles = relevant_ids.len();
+    transitioned.clear();
+    transitioned.extend(&relevant_ids);
+
+    // Particles are sent in the periodic image closest to the camera.
+    let coordinates = entry.coordinates.view();
+    let splines = entry.splines.view();
+    let relevant_shifts: Vec<[f64; 3]> = relevant_ids
+        .iter()
+        .map(|id| {
+            let id = *id as usize;
+            let position: [f64; 3] = if !coordinates.is_empty() {
+                std::array::from_fn(|k| coordinates[[id, k]])
+            } else if !splines.is_empty() {
+                std::array::from_fn(|k| splines[[id, 0, k]])
+            } else {
+                camera_position
+            };
+            nearest_image(position, camera_position, options.box_size)
+        })
+        .collect();
+
+    let fields = options
+        .fields
+        .clone()
+        .unwrap_or_else(|| LodField::ALL.to_vec());
+    let origin = if options.relative_to_camera {
+        camera_position
+    } else {
+        [0.0; 3]
+    };
+    let particles = lod::extract_particles::<F>(
+        ParticleArrays {
+            splines: entry.splines.view(),
+            densities: entry.densities.view(),
+            coordinates: entry.coordinates.view(),
+            voronoi_diameter_extended: entry.voronoi_diameter_extended.view(),
+        },
+        &relevant_ids,
+        &relevant_shifts,
+        origin,
+        &fields,
+    );
+    let interpolated = options.time.map(|time| {
+        lod::interpolate_particles::<F>(
+            entry.splines.view(),
+            entry.densities.view(),
+            &relevant_ids,
+            &relevant_shifts,
+            origin,
+            time,
+        )
+    });
+    let color = color_field.map(|(name, field)| lod::color_values(name, field, &relevant_ids));
+
+    Ok(LodResult {
+        splines_a: particles.splines_a,
+        splines_b: particles.splines_b,
+        splines_c: particles.splines_c,
+        splines_d: particles.splines_d,
+        relevant_densities_flat: particles.relevant_densities_flat,
+        relevant_coordinates: particles.relevant_coordinates,
+        relevant_voronoi_diameter_extended: particles.relevant_voronoi_diameter_extended,
+        // Filled in for clients without a session, which keep track of the lod themselves.
+        client_level_of_detail: None,
+        min_d: particles.min_d,
+        max_d: particles.max_d,
+        n_particles,
+        fields,
+        snapshot_id,
+        node_indices: None,
+        origin: options.relative_to_camera.then_some(origin),
+        color,
+        interpolated,
+        // Transitions do not refine the view.
+        refined: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_received_particles() {
+        let particle_list_of_leafs = array![5, 6, 7, 8, 9, 10, 11];
+        let particle_list_of_leafs_scan = array![0, 3, 5];
+        // Node 1 is fully sent, node 2 has one batch of two and 7 is not a node.
+        let level_of_detail = HashMap::from([(2, 1), (1, 4), (0, 0), (7, 1)]);
+
+        let received = received_particles(
+            particle_list_of_leafs.view(),
+            particle_list_of_leafs_scan.view(),
+            &level_of_detail,
+            &HashMap::new(),
+            2,
+        );
+        assert_eq!(received, vec![8, 9, 10, 11]);
+
+        // Node 2 is closer to the camera, node 1 is out of view.
+        let received = received_particles(
+            particle_list_of_leafs.view(),
+            particle_list_of_leafs_scan.view(),
+            &level_of_detail,
+            &HashMap::from([(0, 5.0), (2, 1.0)]),
+            2,
+        );
+        assert_eq!(received, vec![10, 11, 8, 9]);
+    }
+
+    #[test]
+    fn test_match_particles() {
+        let ids = array![100, 101, 102, 103];
+        // Particle 101 is gone in the next snapshot, the others are shuffled.
+        let next_ids = array![103, 104, 100, 102];
+
+        let matched = match_particles(&[3, 0, 1], ids.view(), next_ids.view());
+        assert_eq!(matched, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_nearest_image() {
+        assert_eq!(
+            nearest_image([95.0, 50.0, 5.0], [5.0, 50.0, 5.0], Some(100.0)),
+            [-100.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            nearest_image([95.0, 50.0, 5.0], [5.0, 50.0, 5.0], None),
+            [0.0; 3]
+        );
+    }
+}
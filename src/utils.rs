@@ -2,6 +2,7 @@ use anyhow::Context;
 use regex::Regex;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 pub fn search_folders_matching_regex<P: AsRef<Path>>(
     path: P,
@@ -31,3 +32,42 @@ pub fn search_folders_matching_regex<P: AsRef<Path>>(
 
     Ok(matching_folders)
 }
+
+/// Find the ids of all `snapdir_NNN` folders of a simulation, sorted ascending.
+pub fn available_snapshots(basedir: &str, simulation: &str) -> anyhow::Result<Vec<usize>> {
+    let basedir = basedir.to_string() + "/" + simulation + "/";
+    let regex = Regex::new("snapdir_.*").context("Failed to generate regex.")?;
+    let matching_folders =
+        search_folders_matching_regex(basedir, &regex).context("Failed to query for snapdirs")?;
+
+    let mut snapshot_ids = matching_folders
+        .iter()
+        .map(|folder| {
+            let folder_name = folder
+                .file_name()
+                .context("Failed to get filename.")?
+                .to_string_lossy()
+                .replace("snapdir_", "");
+            usize::from_str(&folder_name).context("Failed to convert to usize")
+        })
+        .collect::<anyhow::Result<Vec<usize>>>()?;
+    snapshot_ids.sort_unstable();
+    Ok(snapshot_ids)
+}
+
+/// Reads the total amount of physical memory from `/proc/meminfo`.
+pub fn total_memory_bytes() -> anyhow::Result<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .context("MemTotal missing in /proc/meminfo")?;
+    // Format: "MemTotal:       16318480 kB"
+    let kilobytes = line
+        .split_whitespace()
+        .nth(1)
+        .context("Malformed MemTotal line")?
+        .parse::<usize>()
+        .context("Failed to parse MemTotal")?;
+    Ok(kilobytes * 1024)
+}
@@ -0,0 +1,137 @@
+use actix::Addr;
+use actix_web::web;
+use serde::Serialize;
+use std::sync::RwLock;
+
+use super::data_cache::{DataCache, LoadRequest};
+use super::dto::PreloadEntry;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupState {
+    Pending,
+    Loading,
+    Loaded,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct WarmupItem {
+    pub simulation: String,
+    pub snapshot_id: usize,
+    pub state: WarmupState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Progress of loading the snapshots listed under `preload` in the config.
+#[derive(Serialize, Clone)]
+pub struct WarmupStatus {
+    /// All preload entries were processed. Failed entries do not block readiness.
+    pub ready: bool,
+    pub n_loaded: usize,
+    pub n_failed: usize,
+    pub items: Vec<WarmupItem>,
+}
+
+impl WarmupStatus {
+    pub fn new(preload: &[PreloadEntry]) -> Self {
+        let items: Vec<WarmupItem> = preload
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .snapshot_ids()
+                    .into_iter()
+                    .map(move |snapshot_id| WarmupItem {
+                        simulation: entry.simulation.clone(),
+                        snapshot_id,
+                        state: WarmupState::Pending,
+                        error: None,
+                    })
+            })
+            .collect();
+        WarmupStatus {
+            ready: items.is_empty(),
+            n_loaded: 0,
+            n_failed: 0,
+            items,
+        }
+    }
+
+    fn update(&mut self, idx: usize, state: WarmupState, error: Option<String>) {
+        self.items[idx].state = state;
+        self.items[idx].error = error;
+        self.n_loaded = self.count(WarmupState::Loaded);
+        self.n_failed = self.count(WarmupState::Failed);
+        self.ready = self.n_loaded + self.n_failed == self.items.len();
+    }
+
+    fn count(&self, state: WarmupState) -> usize {
+        self.items.iter().filter(|item| item.state == state).count()
+    }
+}
+
+/// Load the warm set one snapshot after the other, in the order of the config.
+pub async fn run_warmup(cache: Addr<DataCache>, status: web::Data<RwLock<WarmupStatus>>) {
+    let items = status
+        .read()
+        .expect("Warmup status lock is poisoned.")
+        .items
+        .clone();
+    let n_items = items.len();
+
+    for (idx, item) in items.into_iter().enumerate() {
+        log::info!(
+            "warmup {}/{}: loading {} snapdir_{:03}",
+            idx + 1,
+            n_items,
+            item.simulation,
+            item.snapshot_id
+        );
+        status
+            .write()
+            .expect("Warmup status lock is poisoned.")
+            .update(idx, WarmupState::Loading, None);
+
+        let message = LoadRequest {
+            simulation: item.simulation.clone(),
+            snapshot_id: item.snapshot_id,
+        };
+        let (state, error) = match cache.send(message).await {
+            Ok(Ok(size_in_bytes)) => {
+                log::info!(
+                    "warmup {}/{}: loaded {} snapdir_{:03} ({} MiB)",
+                    idx + 1,
+                    n_items,
+                    item.simulation,
+                    item.snapshot_id,
+                    size_in_bytes / (1024 * 1024)
+                );
+                (WarmupState::Loaded, None)
+            }
+            Ok(Err(err)) => {
+                log::warn!(
+                    "warmup {}/{}: failed to load {} snapdir_{:03}: {:?}",
+                    idx + 1,
+                    n_items,
+                    item.simulation,
+                    item.snapshot_id,
+                    err
+                );
+                (WarmupState::Failed, Some(format!("{:?}", err)))
+            }
+            Err(err) => {
+                log::warn!("warmup: communication with data cache failed {:?}", err);
+                (WarmupState::Failed, Some(format!("{:?}", err)))
+            }
+        };
+        status
+            .write()
+            .expect("Warmup status lock is poisoned.")
+            .update(idx, state, error);
+    }
+
+    if n_items > 0 {
+        log::info!("warmup finished");
+    }
+}
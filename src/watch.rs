@@ -0,0 +1,68 @@
+use actix::prelude::*;
+use notify::{Event, RecommendedWatcher};
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Sent by the file watcher when something inside a watched snapdir changed.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct FilesChanged {
+    pub paths: Vec<PathBuf>,
+}
+
+/// Modification time and size of the files an entry was loaded from.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct FileFingerprint {
+    pub files: Vec<(PathBuf, Option<SystemTime>, u64)>,
+}
+
+impl FileFingerprint {
+    pub fn of(paths: &[PathBuf]) -> Self {
+        let files = paths
+            .iter()
+            .map(|path| match fs::metadata(path) {
+                Ok(metadata) => (path.clone(), metadata.modified().ok(), metadata.len()),
+                Err(_) => (path.clone(), None, 0),
+            })
+            .collect();
+        FileFingerprint { files }
+    }
+
+    /// Whether any of the files was modified, replaced or removed since the fingerprint was taken.
+    pub fn is_stale(&self) -> bool {
+        let paths: Vec<PathBuf> = self.files.iter().map(|(path, _, _)| path.clone()).collect();
+        *self != FileFingerprint::of(&paths)
+    }
+}
+
+/// Create a watcher that forwards all non-access events to `recipient`.
+pub fn file_watcher(recipient: Recipient<FilesChanged>) -> notify::Result<RecommendedWatcher> {
+    notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            if !event.kind.is_access() {
+                recipient.do_send(FilesChanged { paths: event.paths });
+            }
+        }
+        Err(err) => log::warn!("file watcher error {:?}", err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_detects_changes() {
+        let path = std::env::temp_dir().join("cache_server_fingerprint_test.npy");
+        fs::write(&path, b"abc").unwrap();
+        let fingerprint = FileFingerprint::of(&[path.clone()]);
+        assert!(!fingerprint.is_stale());
+
+        fs::write(&path, b"abcdef").unwrap();
+        assert!(fingerprint.is_stale());
+
+        fs::remove_file(&path).unwrap();
+        assert!(fingerprint.is_stale());
+    }
+}